@@ -0,0 +1,56 @@
+use clap::Parser;
+use example_tokio_uds_fd::mime::{Detectors, MimeTypes};
+use example_tokio_uds_fd::policy::Policy;
+use example_tokio_uds_fd::serve::serve;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::exit;
+use std::sync::Arc;
+use tokio::net::UnixListener;
+
+// long running fd broker
+// runs with whatever rights it was started with and hands out fds to
+// unprivileged clients, but only for what the policy allows
+
+#[derive(Debug, Parser)]
+pub struct Opts {
+    /// policy file of allowed path prefixes
+    #[clap(short, long)]
+    policy: PathBuf,
+    /// extra extension to mime type mappings, in the mime.types format
+    #[clap(long)]
+    mime_types: Option<PathBuf>,
+    /// path to create socket at
+    socket_path: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let opts: Opts = Opts::parse();
+    let table = MimeTypes::discover(None, opts.mime_types.as_deref())?;
+    let detectors = Detectors::default().with_system_types(MimeTypes::system()?).with_mime_types(table);
+    let policy = Policy::load(&opts.policy)?.with_detectors(detectors);
+
+    if opts.socket_path.exists() {
+        fs::remove_file(&opts.socket_path)?;
+    }
+
+    println!("brokering {} on socket: {}", opts.policy.display(), opts.socket_path.display());
+    let listener = UnixListener::bind(&opts.socket_path)?;
+    // any local user may ask, the policy decides
+    fs::set_permissions(&opts.socket_path, fs::Permissions::from_mode(0o666))?;
+
+    ctrlc::set_handler({
+        let sock = opts.socket_path.clone();
+        move || {
+            println!("\ndone...");
+            let _ = fs::remove_file(&sock);
+            exit(0);
+        }
+    })
+    .expect("ctrl+c");
+
+    println!("listening...");
+    serve(listener, Arc::new(policy)).await
+}
@@ -1,53 +1,375 @@
+// what rx does with a received file
+//
+// a chain of consumers, each one gets the Msg in turn and either passes it
+// on or ends the chain for that file. built from specs on the command line:
+//
+//   print              metadata, listing or preview
+//   hash[:algo,..]     digests in one pass, verified against the sender's
+//   copy:DIR           materialize files beneath DIR
+//   tar:FILE[:gz|zst]  one archive of everything, .gz or .zst compressed, - for stdout
+//   store[:algo]:DIR   content addressed, each distinct content kept once, blake3 by default
+//
+// consumers named in sealed only get content that can't change, ie a sealed
+// memfd. anything else is watched, copy, tar and store discard what they made
+// of it if it changed, and the chain stops.
+
+mod archiver;
+mod beneath;
+mod copier;
+mod guard;
+mod hasher;
+mod printer;
+mod store;
+
+pub use archiver::Archiver;
+pub use copier::Copier;
+pub use guard::{Guard, RequireSealed};
+pub use hasher::Hasher;
+pub use printer::Printer;
+pub use store::Store;
+
+use crate::hash::Algorithm;
+use crate::preview::PreviewOpts;
+use crate::report::{self, Format};
 use crate::Msg;
-use futures_util::TryStreamExt;
-use sha2::{Digest, Sha256};
-use std::sync::{Arc, Mutex};
+use anyhow::bail;
+use async_trait::async_trait;
+use serde_json::json;
+use futures_util::FutureExt;
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
 use tokio::sync::mpsc::Receiver;
-use tokio_util::codec::{BytesCodec, FramedRead};
-
-pub async fn consume(mut rx: Receiver<Msg>) {
-    while let Some(msg) = rx.recv().await {
-        println!("<consumer id={}>", msg.id);
-        println!("Received {:?} metadata (payload1):", msg.metadata.file_type);
-        println!("\tPath: {}", msg.metadata.path);
-        println!("\tType: {:?}", msg.metadata.file_type);
-        println!("\tSize: {} bytes", msg.metadata.size);
-        println!("\tPermissions: {:o}", msg.metadata.permissions);
-        println!("\tMIME: {}", msg.metadata.mime_type);
-        println!("\tExecutable: {}", msg.metadata.is_executable);
-        println!("\tFile Size: {}", msg.metadata.size);
-
-        let hasher = Arc::new(Mutex::new(Sha256::new()));
-        let hasher_stream = Arc::clone(&hasher);
-
-        let f = tokio::fs::File::from_std(msg.file);
-        let mut stream = FramedRead::new(f, BytesCodec::new())
-        .map_ok(|chunk| chunk.freeze())
-        .inspect_ok(move |bytes| {
-            // assuming the mutex cannot be poisoned here because there is no concurrent access
-            let mut guard = hasher_stream.lock().unwrap_or_else(|e| e.into_inner());
-            guard.update(bytes);
-        });
-
-        if let Some(chunk) = stream.try_next().await.unwrap() {
-            if let Ok(contents) = std::str::from_utf8(&chunk) {
-                if msg.metadata.size > 128 {
-                    println!("\tpreview:\n{}", &contents[..128]);
-                } else {
-                    println!("\tcontent:\n{contents}");
+use tokio::task::JoinSet;
+
+pub enum Outcome {
+    /// hand the message to the next consumer
+    Pass(Box<Msg>),
+    /// nothing more to do with this file
+    Done,
+}
+
+#[async_trait]
+pub trait Consumer: Send + Sync {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome>;
+
+    /// called once after the last message, eg to write the end of an archive
+    async fn finish(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+const NAMES: [&str; 5] = ["print", "hash", "copy", "tar", "store"];
+
+#[derive(Default)]
+pub struct Chain(pub Vec<Box<dyn Consumer>>);
+
+impl Chain {
+    // hash specs without algorithms use the defaults
+    // mmap lets hashers map files that aren't sealed, verify has stores hash existing objects before reuse
+    pub fn from_specs(
+        specs: &[String],
+        hash: &[Algorithm],
+        mmap: bool,
+        verify: bool,
+        preview: PreviewOpts,
+        sealed: &[String],
+    ) -> anyhow::Result<Self> {
+        if let Some(name) = sealed.iter().find(|s| !NAMES.contains(&s.as_str())) {
+            bail!("unknown consumer {name}");
+        }
+        let mut chain = Self::default();
+        for spec in specs {
+            let (name, arg) = match spec.split_once(':') {
+                Some((name, arg)) => (name, Some(arg)),
+                None => (spec.as_str(), None),
+            };
+            let consumer: Box<dyn Consumer> = match (name, arg) {
+                ("print", None) => Box::new(Printer::new(preview)),
+                ("hash", None) => Box::new(Hasher::new(hash.to_vec()).with_mmap(mmap)),
+                ("hash", Some(algos)) => {
+                    let algos = algos.split(',').map(Algorithm::from_str).collect::<anyhow::Result<_>>()?;
+                    Box::new(Hasher::new(algos).with_mmap(mmap))
                 }
+                ("copy", Some(dir)) => Box::new(Copier::new(dir)),
+                ("tar", Some(path)) => Box::new(Archiver::create(path)?),
+                ("store", Some(arg)) => match arg.split_once(':').map(|(a, dir)| (Algorithm::from_str(a), dir)) {
+                    Some((Ok(algorithm), dir)) => Box::new(Store::new(dir, algorithm).with_verify(verify)),
+                    _ => Box::new(Store::new(arg, Algorithm::Blake3).with_verify(verify)),
+                },
+                _ => bail!("unknown consumer {spec}"),
+            };
+            if sealed.iter().any(|s| s == name) {
+                chain.0.push(Box::new(RequireSealed::new(name, consumer)));
+            } else {
+                chain.0.push(consumer);
             }
-        } else {
-            println!("stream error {}", msg.id);
         }
+        Ok(chain)
+    }
+
+    // consumers read with reader, never through the fd's shared offset,
+    // so each one sees the file from the start
+    pub async fn handle(&self, mut msg: Msg) -> anyhow::Result<()> {
+        let report = msg.report.clone();
+        let guard = Guard::new(&msg)?.map(Arc::new);
+        msg.guard = guard.clone();
+        for consumer in &self.0 {
+            let outcome = consumer.handle(msg).await;
+            // the rest of the chain can't trust the content either, whether the consumer failed or not
+            if let Some(guard) = &guard
+                && let Some(change) = guard.check()?
+            {
+                report.error(format!("changed while it was being processed: {change}"));
+                report.set("changed", true);
+                return outcome.map(|_| ());
+            }
+            match outcome? {
+                Outcome::Pass(next) => msg = *next,
+                Outcome::Done => break,
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn finish(&self) -> anyhow::Result<()> {
+        for consumer in &self.0 {
+            consumer.finish().await?;
+        }
+        Ok(())
+    }
+}
+
+// the sender's path without its root or any .., symlinks in the way are beneath's to refuse
+fn relative(path: &str) -> anyhow::Result<PathBuf> {
+    let mut rel = PathBuf::new();
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(c) => rel.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => bail!("{path} can't be made relative"),
+        }
+    }
+    Ok(rel)
+}
 
-        // drain the stream to ensure hash is calculated completely
-        while let Ok(Some(_)) = stream.try_next().await {}
+/// how many files go through the chain at once
+#[derive(Debug, Clone, Copy)]
+pub struct Pool {
+    pub jobs: usize,
+    /// report files in the order they arrived rather than as they finish
+    pub ordered: bool,
+}
 
-        let digest = hasher.lock().unwrap().clone().finalize();
-        let hex: String = digest.iter().map(|b| format!("{b:02x}")).collect();
-        println!("<hash>{hex}</hash>");
+impl Default for Pool {
+    fn default() -> Self {
+        Self { jobs: 1, ordered: false }
+    }
+}
+
+pub async fn consume(mut rx: Receiver<Msg>, chain: Arc<Chain>, format: Format, pool: Pool) {
+    let mut running = JoinSet::new();
+    let mut reorder = Reorder::new(pool.ordered);
+    // ids restart with every connection, the arrival order doesn't
+    let mut seq = 0;
+    let mut open = true;
+    while open || !running.is_empty() {
+        tokio::select! {
+            msg = rx.recv(), if open && running.len() < pool.jobs.max(1) => match msg {
+                Some(msg) => {
+                    let chain = chain.clone();
+                    let s = seq;
+                    seq += 1;
+                    running.spawn(async move { (s, run(&chain, msg, format).await) });
+                }
+                None => open = false,
+            },
+            Some(done) = running.join_next() => {
+                // run catches panics, a task can only fail by being cancelled
+                if let Ok((s, out)) = done {
+                    for out in reorder.push(s, out) {
+                        report::emit(out);
+                    }
+                }
+            }
+        }
+    }
+    if let Err(e) = chain.finish().await {
+        report::error(format, "consumer_error", format!("consumer error: {e}"), json!({"error": e.to_string()}));
+    }
+}
+
+// one file through the chain, rendered
+async fn run(chain: &Chain, msg: Msg, format: Format) -> String {
+    let id = msg.id;
+    let report = msg.report.clone();
+    match AssertUnwindSafe(chain.handle(msg)).catch_unwind().await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => report.error(format!("consumer error {id}: {e}")),
+        Err(_) => report.error(format!("consumer panicked {id}")),
+    }
+    report.render(id, format)
+}
+
+/// holds back output that finished ahead of its turn
+struct Reorder {
+    ordered: bool,
+    next: usize,
+    pending: BTreeMap<usize, String>,
+}
+
+impl Reorder {
+    fn new(ordered: bool) -> Self {
+        Self {
+            ordered,
+            next: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    // whatever is ready to go out now
+    fn push(&mut self, seq: usize, out: String) -> Vec<String> {
+        if !self.ordered {
+            return vec![out];
+        }
+        self.pending.insert(seq, out);
+        let mut ready = vec![];
+        while let Some(out) = self.pending.remove(&self.next) {
+            ready.push(out);
+            self.next += 1;
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::PositionalReader;
+    use crate::testdir::TestDir;
+    use crate::FileMetadata;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::fs::FileExt;
+    use std::sync::Mutex;
+
+    // reads the whole file, and ends the chain if stop is set
+    struct Reader {
+        seen: Arc<Mutex<Vec<String>>>,
+        stop: bool,
+    }
+
+    #[async_trait]
+    impl Consumer for Reader {
+        async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+            let mut contents = String::new();
+            PositionalReader::new(msg.file.try_clone()?).read_to_string(&mut contents)?;
+            self.seen.lock().unwrap().push(contents);
+            Ok(if self.stop { Outcome::Done } else { Outcome::Pass(Box::new(msg)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_leaves_the_offset_and_stops() -> anyhow::Result<()> {
+        let dir = TestDir::new("consumer");
+        let path = dir.join("f");
+        std::fs::write(&path, "abc")?;
+        let seen = Arc::new(Mutex::new(vec![]));
+        let reader = |stop| Box::new(Reader { seen: seen.clone(), stop });
+        let chain = Chain(vec![reader(false), reader(true), reader(false)]);
+        // the sender's offset, wherever it left it
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(1))?;
+        chain.handle(Msg::new(1, FileMetadata::lstat(&path)?, file.try_clone()?)).await?;
+        assert_eq!(*seen.lock().unwrap(), ["abc", "abc"]);
+        assert_eq!(file.stream_position()?, 1);
+
+        assert!(Chain::from_specs(&["print".into(), "hash:blake3,sha1".into()], &[], false, false, PreviewOpts::default(), &[]).is_ok());
+        assert!(Chain::from_specs(&["hash:md5".into()], &[], false, false, PreviewOpts::default(), &[]).is_err());
+        assert!(Chain::from_specs(&["copy".into()], &[], false, false, PreviewOpts::default(), &[]).is_err());
+        assert!(Chain::from_specs(&["bogus".into()], &[], false, false, PreviewOpts::default(), &[]).is_err());
+        assert!(Chain::from_specs(&["hash".into()], &[], false, false, PreviewOpts::default(), &["hash".into()]).is_ok());
+        assert!(Chain::from_specs(&["hash".into()], &[], false, false, PreviewOpts::default(), &["bogus".into()]).is_err());
+        Ok(())
+    }
+
+    // appends to the file, like a sender still writing it
+    struct Writer;
+
+    #[async_trait]
+    impl Consumer for Writer {
+        async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+            msg.file.write_all_at(b"def", 3)?;
+            Ok(Outcome::Pass(Box::new(msg)))
+        }
+    }
+
+    // changes the file, then fails
+    struct Breaker;
+
+    #[async_trait]
+    impl Consumer for Breaker {
+        async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+            msg.file.write_all_at(b"def", 3)?;
+            bail!("broke it")
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_and_unsealed_content_are_caught() -> anyhow::Result<()> {
+        let dir = TestDir::new("consumer-guard");
+        let path = dir.join("f");
+        std::fs::write(&path, "abc")?;
+        let seen = Arc::new(Mutex::new(vec![]));
+        let reader = || Box::new(Reader { seen: seen.clone(), stop: false });
+
+        // nothing reads it once it has changed
+        let chain = Chain(vec![Box::new(Writer), reader()]);
+        let msg = Msg::new(1, FileMetadata::lstat(&path)?, File::options().read(true).write(true).open(&path)?);
+        let report = msg.report.clone();
+        chain.handle(msg).await?;
+        assert!(seen.lock().unwrap().is_empty());
+        assert!(report.render(1, Format::Json).contains("\"changed\":true"));
+
+        // also when the consumer that saw it fails
+        std::fs::write(&path, "abc")?;
+        let chain = Chain(vec![Box::new(Breaker)]);
+        let msg = Msg::new(1, FileMetadata::lstat(&path)?, File::options().read(true).write(true).open(&path)?);
+        let report = msg.report.clone();
+        assert!(chain.handle(msg).await.is_err());
+        assert!(report.render(1, Format::Json).contains("\"changed\":true"));
+
+        // only a sealed memfd gets past RequireSealed
+        let chain = Chain(vec![Box::new(RequireSealed::new("read", reader()))]);
+        let msg = Msg::new(2, FileMetadata::lstat(&path)?, File::open(&path)?);
+        let report = msg.report.clone();
+        chain.handle(msg).await?;
+        assert!(seen.lock().unwrap().is_empty());
+        assert!(report.render(2, Format::Text).contains("read: skipped"));
+        let sealed = crate::memfd::from_reader("sealed", &b"xyz"[..])?;
+        let msg = Msg::new(3, FileMetadata::detached("sealed", &sealed)?, sealed);
+        chain.handle(msg).await?;
+        assert_eq!(*seen.lock().unwrap(), ["xyz"]);
+        Ok(())
+    }
+
+    #[test]
+    fn reorder_holds_back_early_results() {
+        let mut reorder = Reorder::new(true);
+        assert!(reorder.push(1, "b".into()).is_empty());
+        assert!(reorder.push(2, "c".into()).is_empty());
+        assert_eq!(reorder.push(0, "a".into()), ["a", "b", "c"]);
+        assert_eq!(reorder.push(3, "d".into()), ["d"]);
+        assert_eq!(Reorder::new(false).push(5, "x".into()), ["x"]);
+    }
 
-        println!("</consumer>");
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative("/tmp/src/a.txt").unwrap(), Path::new("tmp/src/a.txt"));
+        assert_eq!(relative("./sub/b").unwrap(), Path::new("sub/b"));
+        assert!(relative("../etc/passwd").is_err());
+        assert!(relative("a/../../b").is_err());
     }
 }
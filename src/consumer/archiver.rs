@@ -0,0 +1,312 @@
+use super::guard::{self, Guard};
+use super::{relative, Consumer, Outcome};
+use crate::reader::PositionalReader;
+use crate::{copy, report};
+use crate::{FileMetadata, FileType, Msg};
+use anyhow::Context;
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use nix::fcntl::OFlag;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tar::{Builder, EntryType, Header};
+use tokio::task;
+
+type Archive = Arc<Mutex<Option<Builder<Sink>>>>;
+
+/// streams every received file into one tar archive, gzip or zstd compressed by the file extension
+/// or a `:gz`, `:zst` or `:none` suffix. `-` writes to stdout, and the reports go to stderr instead
+pub struct Archiver {
+    archive: Archive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Archiver {
+    /// FILE or FILE:gz, FILE:zst, FILE:none, eg `-:zst` for a compressed stdout
+    pub fn create(spec: &str) -> anyhow::Result<Self> {
+        let (path, compression) = split_spec(spec);
+        if path == "-" {
+            // nothing else may write to the archive's stdout
+            report::claim_stdout();
+            return Self::to_writer(Box::new(io::stdout()), compression);
+        }
+        let out = File::create(path).with_context(|| format!("failed to create {path}"))?;
+        Self::to_writer(Box::new(out), compression)
+    }
+
+    fn to_writer(out: Box<dyn Write + Send>, compression: Compression) -> anyhow::Result<Self> {
+        let sink = match compression {
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(out, flate2::Compression::default())),
+            Compression::Zstd => Sink::Zstd(zstd::Encoder::new(out, 0)?),
+            Compression::None => Sink::Plain(out),
+        };
+        let mut builder = Builder::new(sink);
+        // symlinks are archived as links, never followed
+        builder.follow_symlinks(false);
+        Ok(Self {
+            archive: Arc::new(Mutex::new(Some(builder))),
+        })
+    }
+}
+
+#[async_trait]
+impl Consumer for Archiver {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+        let archive = self.archive.clone();
+        let metadata = msg.metadata.clone();
+        let file = msg.file.try_clone()?;
+        let guard = msg.guard.clone();
+        // syscalls must be made in blocking context
+        let appended = task::spawn_blocking(move || {
+            let file = settled(file, &metadata, guard.as_deref())?;
+            let mut builder = archive.lock().unwrap_or_else(|e| e.into_inner());
+            match builder.as_mut() {
+                Some(builder) => append(builder, &metadata, file),
+                None => anyhow::bail!("archive already finished"),
+            }
+        })
+        .await?;
+        match &appended {
+            Ok(true) => msg.report.line("\tarchived"),
+            Ok(false) => msg.report.line(format!("\tnot archived: {:?}", msg.metadata.file_type)),
+            Err(e) => msg.report.error(format!("failed to archive: {e}")),
+        }
+        msg.report.set("archived", matches!(appended, Ok(true)));
+        Ok(Outcome::Pass(Box::new(msg)))
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        let archive = self.archive.clone();
+        task::spawn_blocking(move || {
+            let builder = archive.lock().unwrap_or_else(|e| e.into_inner()).take();
+            match builder {
+                Some(builder) => builder.into_inner()?.finish(),
+                None => Ok(()),
+            }
+        })
+        .await?
+    }
+}
+
+// an append can't be taken back, content that can change is copied aside first
+// and only archived if it didn't change while it was copied. the copy is an
+// unnamed file in $TMPDIR rather than memory, the file may be bigger than that
+fn settled(file: File, metadata: &FileMetadata, guard: Option<&Guard>) -> anyhow::Result<File> {
+    if metadata.file_type != FileType::RegularFile || guard.is_none() {
+        return Ok(file);
+    }
+    let spool = File::options()
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(OFlag::O_TMPFILE.bits())
+        .open(std::env::temp_dir())
+        .context("failed to spool")?;
+    copy::copy_data(&file, &spool)?;
+    guard::unchanged(guard)?;
+    Ok(spool)
+}
+
+fn append(builder: &mut Builder<Sink>, metadata: &FileMetadata, file: File) -> anyhow::Result<bool> {
+    let path = relative(&metadata.path)?;
+    let mut header = Header::new_gnu();
+    header.set_mode(metadata.permissions & 0o7777);
+    header.set_mtime(metadata.stat.mtime.secs.max(0) as u64);
+    header.set_uid(metadata.stat.uid as u64);
+    header.set_gid(metadata.stat.gid as u64);
+    match metadata.file_type {
+        FileType::RegularFile => {
+            // the size has to match what is written, take it from the fd rather than the metadata
+            let size = file.metadata()?.len();
+            header.set_entry_type(EntryType::Regular);
+            header.set_size(size);
+            builder.append_data(&mut header, path, Padded::new(PositionalReader::new(file), size))?;
+        }
+        FileType::Directory => {
+            header.set_entry_type(EntryType::Directory);
+            header.set_size(0);
+            builder.append_data(&mut header, path, io::empty())?;
+        }
+        FileType::SymbolicLink => {
+            let Some(target) = &metadata.symlink_target else {
+                return Ok(false);
+            };
+            header.set_entry_type(EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target)?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+// exactly size bytes, a file that shrank since it was stat'd is padded with zeros
+// so the archive stays readable
+struct Padded<R> {
+    inner: R,
+    left: u64,
+    eof: bool,
+}
+
+impl<R: Read> Padded<R> {
+    fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner,
+            left: size,
+            eof: false,
+        }
+    }
+}
+
+impl<R: Read> Read for Padded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        if want == 0 {
+            return Ok(0);
+        }
+        let mut n = if self.eof { 0 } else { self.inner.read(&mut buf[..want])? };
+        if n == 0 {
+            self.eof = true;
+            buf[..want].fill(0);
+            n = want;
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+// a known suffix picks the compression, otherwise the extension does. a path may
+// have colons of its own, only the suffixes are split off
+fn split_spec(spec: &str) -> (&str, Compression) {
+    let by_name = |name: &str| match name {
+        "gz" | "tgz" => Some(Compression::Gzip),
+        "zst" | "tzst" => Some(Compression::Zstd),
+        _ => None,
+    };
+    match spec.rsplit_once(':') {
+        Some((path, "none")) => (path, Compression::None),
+        Some((path, suffix)) if let Some(compression) = by_name(suffix) => (path, compression),
+        _ => {
+            let extension = Path::new(spec).extension().and_then(|e| e.to_str());
+            (spec, extension.and_then(by_name).unwrap_or(Compression::None))
+        }
+    }
+}
+
+enum Sink {
+    Plain(Box<dyn Write + Send>),
+    Gzip(GzEncoder<Box<dyn Write + Send>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write + Send>>),
+}
+
+impl Sink {
+    // compressors have their own trailer to write
+    fn finish(self) -> anyhow::Result<()> {
+        let mut out = match self {
+            Sink::Plain(out) => out,
+            Sink::Gzip(gz) => gz.finish()?,
+            Sink::Zstd(zstd) => zstd.finish()?,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(out) => out.write(buf),
+            Sink::Gzip(gz) => gz.write(buf),
+            Sink::Zstd(zstd) => zstd.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(out) => out.flush(),
+            Sink::Gzip(gz) => gz.flush(),
+            Sink::Zstd(zstd) => zstd.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    // the archive's stdout, in memory
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn the_stream_is_a_tar_archive() -> anyhow::Result<()> {
+        let dir = TestDir::new("archiver");
+        std::fs::create_dir(dir.join("d"))?;
+        std::fs::write(dir.join("d/f"), "content")?;
+        let out = Shared::default();
+        let archiver = Archiver::to_writer(Box::new(out.clone()), Compression::Zstd)?;
+        for path in [dir.join("d"), dir.join("d/f")] {
+            let mut metadata = FileMetadata::lstat(&path)?;
+            if metadata.file_type == FileType::RegularFile {
+                // too long to name a memfd after, and watched so it is spooled first
+                metadata.path = format!("{}/f", "deep/".repeat(60));
+            }
+            let mut msg = Msg::new(0, metadata, File::open(&path)?);
+            msg.guard = Guard::new(&msg)?.map(Arc::new);
+            archiver.handle(msg).await?;
+        }
+        archiver.finish().await?;
+
+        let bytes = out.0.lock().unwrap().clone();
+        let mut archive = tar::Archive::new(zstd::Decoder::new(&bytes[..])?);
+        let mut entries = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            entries.push((entry.path()?.file_name().unwrap().to_string_lossy().to_string(), contents));
+        }
+        assert_eq!(entries, [("d".to_string(), String::new()), ("f".to_string(), "content".to_string())]);
+        Ok(())
+    }
+
+    #[test]
+    fn compression_by_suffix_or_extension() {
+        assert_eq!(split_spec("-"), ("-", Compression::None));
+        assert_eq!(split_spec("-:gz"), ("-", Compression::Gzip));
+        assert_eq!(split_spec("out.tar.zst"), ("out.tar.zst", Compression::Zstd));
+        assert_eq!(split_spec("out.tgz:none"), ("out.tgz", Compression::None));
+        assert_eq!(split_spec("a:b.tar"), ("a:b.tar", Compression::None));
+    }
+
+    #[test]
+    fn padded_reads_exactly_size() -> io::Result<()> {
+        let mut out = vec![];
+        Padded::new(&b"abc"[..], 5).read_to_end(&mut out)?;
+        assert_eq!(out, b"abc\0\0");
+        out.clear();
+        Padded::new(&b"abcdef"[..], 2).read_to_end(&mut out)?;
+        assert_eq!(out, b"ab");
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+// creating things beneath a destination root
+//
+// paths are resolved one component at a time from the root's fd and no
+// symlink is ever followed, so no path a sender names leads outside the
+// root, not even through a symlink an earlier message put in the tree
+
+use anyhow::bail;
+use nix::errno::Errno;
+use nix::fcntl::{openat2, OFlag, OpenHow, ResolveFlag};
+use nix::sys::stat::{mkdirat, Mode};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path};
+
+/// a destination root dir, see relative for making a sender's path fit it
+#[derive(Debug)]
+pub struct Beneath {
+    root: File,
+}
+
+impl Beneath {
+    /// the root itself is the operator's, and created if it's missing
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(root)?;
+        let flags = OFlag::O_PATH | OFlag::O_DIRECTORY;
+        let root = File::options().read(true).custom_flags(flags.bits()).open(root)?;
+        Ok(Self { root })
+    }
+
+    /// rel opened beneath the root, an empty rel is the root
+    pub fn open_at(&self, rel: &Path, flags: OFlag) -> anyhow::Result<File> {
+        open_beneath(&self.root, rel, flags)
+    }
+
+    /// the dir that holds rel, with any that are missing created, and rel's own name
+    pub fn parent<'a>(&self, rel: &'a Path) -> anyhow::Result<(File, &'a OsStr)> {
+        let (Some(parent), Some(name)) = (rel.parent(), rel.file_name()) else {
+            bail!("{} has no name", rel.display());
+        };
+        Ok((self.dir(parent)?, name))
+    }
+
+    /// the dir rel, with any that are missing created
+    pub fn dir(&self, rel: &Path) -> anyhow::Result<File> {
+        let mut dir = self.open_at(Path::new(""), OFlag::O_PATH | OFlag::O_DIRECTORY)?;
+        for c in rel.components() {
+            let Component::Normal(c) = c else {
+                bail!("{} is not relative", rel.display());
+            };
+            mkdir(&dir, c)?;
+            dir = open_beneath(&dir, Path::new(c), OFlag::O_PATH | OFlag::O_DIRECTORY)?;
+        }
+        Ok(dir)
+    }
+}
+
+/// a dir named name in dir, one that is already there is fine
+// whatever is there is checked when it's opened, a symlink won't open as a dir
+pub fn mkdir(dir: &File, name: &OsStr) -> anyhow::Result<()> {
+    match mkdirat(Some(dir.as_raw_fd()), name, Mode::from_bits_truncate(0o777)) {
+        Ok(()) | Err(Errno::EEXIST) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn open_beneath(dir: &File, rel: &Path, flags: OFlag) -> anyhow::Result<File> {
+    let rel = if rel.as_os_str().is_empty() { Path::new(".") } else { rel };
+    let how = OpenHow::new()
+        .flags(flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_NO_SYMLINKS);
+    let fd = openat2(dir.as_raw_fd(), rel, how)?;
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
@@ -0,0 +1,274 @@
+use super::beneath::{self, Beneath};
+use super::guard::{self, Guard};
+use super::{relative, Consumer, Outcome};
+use crate::report::Report;
+use crate::stat::Timestamp;
+use crate::{copy, xattr, FileMetadata, FileType, Msg};
+use anyhow::bail;
+use async_trait::async_trait;
+use nix::fcntl::{openat, renameat, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{symlinkat, unlinkat, UnlinkatFlags};
+use std::cmp::Reverse;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, FileTimes, Permissions};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task;
+
+/// writes every received file beneath a destination root, at the sender's path made relative
+pub struct Copier {
+    root: PathBuf,
+    /// dirs get their mode and times in finish, once everything in them is written
+    dirs: Mutex<Vec<(PathBuf, FileMetadata)>>,
+}
+
+impl Copier {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            dirs: Mutex::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Consumer for Copier {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+        let rel = relative(&msg.metadata.path)?;
+        let root = self.root.clone();
+        let metadata = msg.metadata.clone();
+        let file = msg.file.try_clone()?;
+        let report = msg.report.clone();
+        let guard = msg.guard.clone();
+        // syscalls must be made in blocking context
+        let copied = task::spawn_blocking(move || {
+            let dst = Beneath::open(&root)?;
+            materialize(&dst, &file, &metadata, &rel, &report, guard.as_deref()).map(|done| (rel, done))
+        })
+        .await?;
+        match copied {
+            Ok((rel, Some(done))) => {
+                let done = self.root.join(done).display().to_string();
+                msg.report.line(format!("\tcopied: {done}"));
+                msg.report.set("copied", done);
+                if msg.metadata.file_type == FileType::Directory {
+                    self.dirs.lock().unwrap_or_else(|e| e.into_inner()).push((rel, msg.metadata.clone()));
+                }
+            }
+            Ok((_, None)) => msg.report.line(format!("\tnot copied: {:?}", msg.metadata.file_type)),
+            Err(e) => msg.report.error(format!("failed to copy: {e}")),
+        }
+        Ok(Outcome::Pass(Box::new(msg)))
+    }
+
+    // a read-only dir can't take new entries, so modes go on last
+    async fn finish(&self) -> anyhow::Result<()> {
+        let mut dirs = std::mem::take(&mut *self.dirs.lock().unwrap_or_else(|e| e.into_inner()));
+        let root = self.root.clone();
+        task::spawn_blocking(move || {
+            let dst = Beneath::open(&root)?;
+            // children first, so a parent's mode can't get in the way
+            dirs.sort_by_key(|(rel, _)| Reverse(rel.components().count()));
+            let mut failed = vec![];
+            for (rel, metadata) in &dirs {
+                let res = dst.open_at(rel, OFlag::O_RDONLY | OFlag::O_DIRECTORY).and_then(|dir| {
+                    dir.set_permissions(Permissions::from_mode(metadata.permissions & 0o7777))?;
+                    Ok(dir.set_times(times(metadata))?)
+                });
+                if let Err(e) = res {
+                    failed.push(format!("{}: {e}", rel.display()));
+                }
+            }
+            if !failed.is_empty() {
+                bail!("failed to finish dirs {}", failed.join(", "));
+            }
+            Ok(())
+        })
+        .await?
+    }
+}
+
+// what was made, relative to the root
+fn materialize(
+    dst: &Beneath,
+    file: &File,
+    metadata: &FileMetadata,
+    rel: &Path,
+    report: &Report,
+    guard: Option<&Guard>,
+) -> anyhow::Result<Option<String>> {
+    match metadata.file_type {
+        FileType::Directory => {
+            let (dir, name) = dst.parent(rel)?;
+            beneath::mkdir(&dir, name)?;
+            // fails if what is there isn't a dir
+            dst.open_at(rel, OFlag::O_PATH | OFlag::O_DIRECTORY)?;
+            Ok(Some(format!("{} (dir)", rel.display())))
+        }
+        FileType::SymbolicLink => {
+            let Some(target) = &metadata.symlink_target else {
+                return Ok(None);
+            };
+            let (dir, name) = dst.parent(rel)?;
+            let tmp = temp_name(name);
+            symlinkat(target.as_str(), Some(dir.as_raw_fd()), tmp.as_os_str())?;
+            if let Err(e) = renameat(Some(dir.as_raw_fd()), tmp.as_os_str(), Some(dir.as_raw_fd()), name) {
+                let _ = unlinkat(Some(dir.as_raw_fd()), tmp.as_os_str(), UnlinkatFlags::NoRemoveDir);
+                return Err(e.into());
+            }
+            Ok(Some(format!("{} -> {target}", rel.display())))
+        }
+        FileType::RegularFile => {
+            let (dir, name) = dst.parent(rel)?;
+            let tmp = temp_name(name);
+            let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+            let fd = openat(Some(dir.as_raw_fd()), tmp.as_os_str(), flags, Mode::from_bits_truncate(0o600))?;
+            let out = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            let res = copy_into(file, &out, metadata, report).and_then(|method| {
+                // a copy of content that changed meanwhile never takes the place of the file
+                guard::unchanged(guard)?;
+                renameat(Some(dir.as_raw_fd()), tmp.as_os_str(), Some(dir.as_raw_fd()), name)?;
+                Ok(method)
+            });
+            match res {
+                Ok(method) => Ok(Some(format!("{} ({method})", rel.display()))),
+                Err(e) => {
+                    let _ = unlinkat(Some(dir.as_raw_fd()), tmp.as_os_str(), UnlinkatFlags::NoRemoveDir);
+                    Err(e)
+                }
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+// data, then xattrs, mode and times, so the file is complete before it is renamed into place
+fn copy_into(src: &File, out: &File, metadata: &FileMetadata, report: &Report) -> anyhow::Result<copy::Method> {
+    let method = copy::copy_data(src, out)?;
+    for (name, e) in xattr::apply(out, &metadata.xattrs) {
+        report.error(format!("failed to set xattr {name}: {e}"));
+    }
+    out.set_permissions(Permissions::from_mode(metadata.permissions & 0o7777))?;
+    out.set_times(times(metadata))?;
+    out.sync_all()?;
+    Ok(method)
+}
+
+// a hidden sibling, so the rename stays in one dir
+fn temp_name(name: &OsStr) -> OsString {
+    format!(".{}.{}.tmp", name.to_string_lossy(), std::process::id()).into()
+}
+
+fn times(metadata: &FileMetadata) -> FileTimes {
+    FileTimes::new()
+        .set_accessed(system_time(metadata.stat.atime))
+        .set_modified(system_time(metadata.stat.mtime))
+}
+
+fn system_time(t: Timestamp) -> SystemTime {
+    let nanos = Duration::from_nanos(t.nanos as u64);
+    match u64::try_from(t.secs) {
+        Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs) + nanos,
+        Err(_) => UNIX_EPOCH - Duration::from_secs(t.secs.unsigned_abs()) + nanos,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Format;
+    use crate::testdir::TestDir;
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+    // a message for src as the sender would send it from path
+    fn msg(path: &str, src: &Path) -> anyhow::Result<Msg> {
+        let mut metadata = FileMetadata::lstat(src)?;
+        metadata.path = path.to_string();
+        let flags = if metadata.file_type == FileType::SymbolicLink { OFlag::O_PATH | OFlag::O_NOFOLLOW } else { OFlag::empty() };
+        let file = File::options().read(true).custom_flags(flags.bits()).open(src)?;
+        Ok(Msg::new(0, metadata, file))
+    }
+
+    async fn copy(copier: &Copier, path: &str, src: &Path) -> anyhow::Result<String> {
+        let msg = msg(path, src)?;
+        let report = msg.report.clone();
+        copier.handle(msg).await?;
+        Ok(report.render(0, Format::Text))
+    }
+
+    #[tokio::test]
+    async fn symlinks_in_the_tree_lead_nowhere() -> anyhow::Result<()> {
+        let dir = TestDir::new("copier-escape");
+        fs::create_dir_all(dir.join("src/sub"))?;
+        fs::create_dir_all(dir.join("outside"))?;
+        fs::write(dir.join("src/f"), "data")?;
+        std::os::unix::fs::symlink(dir.join("outside"), dir.join("src/link"))?;
+        let copier = Copier::new(dir.join("dst"));
+
+        assert!(!copy(&copier, "/a", &dir.join("src/link")).await?.contains("failed"));
+        assert_eq!(fs::read_link(dir.join("dst/a"))?, dir.join("outside"));
+        assert!(copy(&copier, "/a/x", &dir.join("src/f")).await?.contains("failed to copy"));
+        assert!(copy(&copier, "/a/b/x", &dir.join("src/f")).await?.contains("failed to copy"));
+        assert!(copy(&copier, "/a", &dir.join("src/sub")).await?.contains("failed to copy"));
+        assert_eq!(fs::read_dir(dir.join("outside"))?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn files_are_renamed_into_place_with_modes_and_times() -> anyhow::Result<()> {
+        let dir = TestDir::new("copier");
+        let (src, dst) = (dir.join("src"), dir.join("dst"));
+        fs::create_dir_all(src.join("d"))?;
+        fs::write(src.join("d/f"), "new")?;
+        let then = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let f = File::open(src.join("d/f"))?;
+        f.set_permissions(Permissions::from_mode(0o640))?;
+        f.set_times(FileTimes::new().set_modified(then))?;
+        let d = File::open(src.join("d"))?;
+        d.set_times(FileTimes::new().set_modified(then))?;
+        d.set_permissions(Permissions::from_mode(0o555))?;
+
+        // an older copy, linked from elsewhere so an in place overwrite would show
+        fs::create_dir_all(dst.join("d"))?;
+        fs::write(dst.join("d/f"), "old")?;
+        fs::hard_link(dst.join("d/f"), dst.join("old"))?;
+
+        let copier = Copier::new(&dst);
+        copy(&copier, "/d", &src.join("d")).await?;
+        copy(&copier, "/d/f", &src.join("d/f")).await?;
+        copier.finish().await?;
+
+        assert_eq!(fs::read_to_string(dst.join("d/f"))?, "new");
+        assert_eq!(fs::read_to_string(dst.join("old"))?, "old");
+        let names: Vec<_> = fs::read_dir(dst.join("d"))?.map(|e| e.map(|e| e.file_name())).collect::<Result<_, _>>()?;
+        assert_eq!(names, ["f"]);
+        for (name, mode) in [("d", 0o555), ("d/f", 0o640)] {
+            let m = fs::symlink_metadata(dst.join(name))?;
+            assert_eq!(m.mode() & 0o7777, mode, "{name}");
+            assert_eq!(m.modified()?, then, "{name}");
+        }
+        fs::set_permissions(src.join("d"), Permissions::from_mode(0o755))?;
+        fs::set_permissions(dst.join("d"), Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn changed_files_are_discarded() -> anyhow::Result<()> {
+        let dir = TestDir::new("copier-changed");
+        fs::write(dir.join("f"), "abc")?;
+        let mut msg = msg("/f", &dir.join("f"))?;
+        msg.guard = Guard::new(&msg)?.map(std::sync::Arc::new);
+        let report = msg.report.clone();
+        fs::write(dir.join("f"), "abcdef")?;
+
+        Copier::new(dir.join("dst")).handle(msg).await?;
+        assert!(report.render(0, Format::Text).contains("changed, discarded: size 3 -> 6"));
+        assert_eq!(fs::read_dir(dir.join("dst"))?.count(), 0);
+        Ok(())
+    }
+}
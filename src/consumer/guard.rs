@@ -0,0 +1,100 @@
+use super::{Consumer, Outcome};
+use crate::stat::Timestamp;
+use crate::{memfd, FileType, Msg};
+use async_trait::async_trait;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+/// only hands content that can't change, ie a sealed memfd, to the consumer
+/// anything else skips it, with an error in the report
+pub struct RequireSealed {
+    name: String,
+    consumer: Box<dyn Consumer>,
+}
+
+impl RequireSealed {
+    pub fn new(name: &str, consumer: Box<dyn Consumer>) -> Self {
+        Self {
+            name: name.to_string(),
+            consumer,
+        }
+    }
+}
+
+#[async_trait]
+impl Consumer for RequireSealed {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+        // only regular files have content to seal
+        if msg.metadata.file_type == FileType::RegularFile && !memfd::is_immutable(&msg.file) {
+            msg.report.error(format!("{}: skipped, the content isn't sealed", self.name));
+            return Ok(Outcome::Pass(Box::new(msg)));
+        }
+        self.consumer.handle(msg).await
+    }
+
+    async fn finish(&self) -> anyhow::Result<()> {
+        self.consumer.finish().await
+    }
+}
+
+/// watches a regular file for the sender changing it while the chain works on it
+// fstat before and after, any write moves the mtime and ctime even when the size stays
+#[derive(Debug)]
+pub struct Guard {
+    file: File,
+    before: Snapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Snapshot {
+    size: u64,
+    mtime: Timestamp,
+    ctime: Timestamp,
+}
+
+impl Snapshot {
+    fn of(file: &File) -> io::Result<Self> {
+        let m = file.metadata()?;
+        let ts = |secs, nanos: i64| Timestamp { secs, nanos: nanos as u32 };
+        Ok(Self {
+            size: m.len(),
+            mtime: ts(m.mtime(), m.mtime_nsec()),
+            ctime: ts(m.ctime(), m.ctime_nsec()),
+        })
+    }
+}
+
+impl Guard {
+    /// nothing to watch for anything but a regular file, or for a sealed memfd
+    pub fn new(msg: &Msg) -> io::Result<Option<Self>> {
+        if msg.metadata.file_type != FileType::RegularFile || memfd::is_immutable(&msg.file) {
+            return Ok(None);
+        }
+        let file = msg.file.try_clone()?;
+        let before = Snapshot::of(&file)?;
+        Ok(Some(Self { file, before }))
+    }
+
+    /// what changed since the guard was made, if anything
+    pub fn check(&self) -> io::Result<Option<String>> {
+        let (b, a) = (self.before, Snapshot::of(&self.file)?);
+        Ok(if a.size != b.size {
+            Some(format!("size {} -> {}", b.size, a.size))
+        } else if a.mtime != b.mtime {
+            Some(format!("mtime {}.{:09} -> {}.{:09}", b.mtime.secs, b.mtime.nanos, a.mtime.secs, a.mtime.nanos))
+        } else if a.ctime != b.ctime {
+            Some(format!("ctime {}.{:09} -> {}.{:09}", b.ctime.secs, b.ctime.nanos, a.ctime.secs, a.ctime.nanos))
+        } else {
+            None
+        })
+    }
+}
+
+/// for consumers to call right before they commit what they made of the file, fails once it has changed
+pub fn unchanged(guard: Option<&Guard>) -> anyhow::Result<()> {
+    if let Some(change) = guard.map(Guard::check).transpose()?.flatten() {
+        anyhow::bail!("changed, discarded: {change}");
+    }
+    Ok(())
+}
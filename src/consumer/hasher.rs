@@ -0,0 +1,75 @@
+use super::{Consumer, Outcome};
+use crate::hash::{Algorithm, MultiHasher};
+use crate::{reader, FileType, Msg};
+use async_trait::async_trait;
+use std::io;
+use tokio::task;
+
+/// hashes regular files with each algorithm in a single pass and checks the sender's digest
+/// the digests are left on the Msg for the rest of the chain
+pub struct Hasher {
+    algorithms: Vec<Algorithm>,
+    /// map big files even when they aren't sealed, see reader::map_unsealed
+    mmap: bool,
+}
+
+impl Hasher {
+    pub fn new(algorithms: Vec<Algorithm>) -> Self {
+        Self { algorithms, mmap: false }
+    }
+
+    pub fn with_mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+}
+
+#[async_trait]
+impl Consumer for Hasher {
+    async fn handle(&self, mut msg: Msg) -> anyhow::Result<Outcome> {
+        if msg.metadata.file_type != FileType::RegularFile {
+            return Ok(Outcome::Pass(Box::new(msg)));
+        }
+
+        // the sender's algorithm too, so there is something to verify against
+        let mut wanted = self.algorithms.clone();
+        wanted.extend(msg.metadata.digest.as_ref().map(|d| d.algorithm));
+        let mut hasher = MultiHasher::new(&wanted);
+
+        // big sealed files are hashed straight from a mapping, without copying through a buffer.
+        // anything else could be truncated under the mapping, unless --mmap says to take that risk
+        let file = msg.file.try_clone()?;
+        let mapped = file.metadata()?.len() >= reader::MAP_THRESHOLD && (self.mmap || reader::mappable(&file));
+        let mmap = self.mmap;
+        // syscalls must be made in blocking context, the whole file is read in one task
+        hasher = task::spawn_blocking(move || -> io::Result<_> {
+            if mapped {
+                let map = if mmap { unsafe { reader::map_unsealed(&file)? } } else { reader::map(&file)? };
+                hasher.update_large(&map);
+            } else {
+                reader::each_chunk(&file, |chunk| hasher.update(chunk))?;
+            }
+            Ok(hasher)
+        })
+        .await??;
+
+        let digests = hasher.finish();
+        let wanted: Vec<_> = digests.iter().filter(|d| self.algorithms.contains(&d.algorithm)).collect();
+        for d in &wanted {
+            msg.report.line(format!("<hash algo={}>{}</hash>", d.algorithm, d.hex));
+        }
+        msg.report.set("digests", &wanted);
+        // a mismatch means the file changed or was truncated after the sender hashed it
+        if let Some(sent) = &msg.metadata.digest {
+            let verified = digests.contains(sent);
+            if verified {
+                msg.report.line(format!("\tdigest: verified {}", sent.algorithm));
+            } else {
+                msg.report.error(format!("digest: MISMATCH, sender had {sent}"));
+            }
+            msg.report.set("verified", verified);
+        }
+        msg.digests = digests;
+        Ok(Outcome::Pass(Box::new(msg)))
+    }
+}
@@ -0,0 +1,132 @@
+use super::{Consumer, Outcome};
+use crate::handle::{DirHandle, PathHandle};
+use crate::report::Report;
+use crate::stat::Attributes;
+use crate::{reader, FileType, Msg};
+use async_trait::async_trait;
+use crate::preview::{self, Encoding, PreviewOpts};
+use serde_json::json;
+
+/// prints the metadata, then a dir listing, a description of an O_PATH fd or a preview
+pub struct Printer {
+    preview: PreviewOpts,
+}
+
+impl Printer {
+    pub fn new(preview: PreviewOpts) -> Self {
+        Self { preview }
+    }
+}
+
+#[async_trait]
+impl Consumer for Printer {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+        let r = &msg.report;
+        let m = &msg.metadata;
+        r.line(format!("Received {:?} metadata (payload1):", m.file_type));
+        r.line(format!("\tPath: {}", m.path));
+        r.line(format!("\tType: {:?}", m.file_type));
+        r.line(format!("\tSize: {} bytes", m.size));
+        r.line(format!("\tPermissions: {:o}", m.permissions));
+        r.line(format!("\tMIME: {}", m.mime_type));
+        r.line(format!("\tExecutable: {}", m.is_executable));
+        r.line(format!("\tFile Size: {}", m.size));
+        let stat = &m.stat;
+        r.line(format!("\tOwner: {}:{}", stat.uid, stat.gid));
+        r.line(format!("\tInode: {} dev: {:#x} links: {} blocks: {}", stat.inode, stat.device, stat.nlink, stat.blocks));
+        r.line(format!("\tModified: {}.{:09}", stat.mtime.secs, stat.mtime.nanos));
+        if let Some(btime) = stat.btime {
+            r.line(format!("\tBorn: {}.{:09}", btime.secs, btime.nanos));
+        }
+        if stat.attributes != Attributes::default() {
+            r.line(format!("\tAttributes: {:?}", stat.attributes));
+        }
+        for x in &m.xattrs {
+            r.line(format!("\tXattr: {} ({} bytes)", x.name, x.value.len()));
+        }
+        if !msg.seals.is_empty() {
+            r.line(format!("\tSeals: {}", msg.seals.join(" ")));
+        }
+        if let Some(target) = &m.symlink_target {
+            let dangling = if m.dangling { " (dangling)" } else { "" };
+            r.line(format!("\tLink: {target}{dangling}"));
+        }
+
+        match m.file_type {
+            FileType::Directory => list_dir(&DirHandle::new(msg.file.try_clone()?), r),
+            FileType::RegularFile => preview(&msg, &self.preview).await?,
+            // an O_PATH fd, there is nothing to read
+            _ => describe(&PathHandle::new(msg.file.try_clone()?), r),
+        }
+        Ok(Outcome::Pass(Box::new(msg)))
+    }
+}
+
+async fn preview(msg: &Msg, opts: &PreviewOpts) -> anyhow::Result<()> {
+    let limit = opts.head_len();
+    // one byte more than is needed tells whether there is more
+    let mut head = reader::head(msg.file.try_clone()?, limit + 1).await?;
+    if head.is_empty() {
+        return Ok(());
+    }
+    let complete = head.len() <= limit;
+    head.truncate(limit);
+    let p = preview::preview(&head, complete, opts);
+    let label = if p.truncated { "preview" } else { "content" };
+    let label = match p.encoding {
+        Encoding::Utf8 => label.to_string(),
+        encoding => format!("{label} ({})", encoding.name()),
+    };
+    msg.report.line(format!("\t{label}:\n{}", p.text));
+    msg.report.set("preview", &p);
+    Ok(())
+}
+
+fn list_dir(dir: &DirHandle, report: &Report) {
+    match dir.entries() {
+        Ok(entries) => {
+            report.line("\tentries:");
+            for e in &entries {
+                report.line(format!("\t\t{} ({:?})", e.name, e.file_type));
+            }
+            let entries: Vec<_> = entries.iter().map(|e| json!({"name": e.name, "type": e.file_type})).collect();
+            report.set("entries", entries);
+        }
+        Err(e) => report.error(format!("failed to list entries: {e}")),
+    }
+}
+
+fn describe(handle: &PathHandle, report: &Report) {
+    match handle.stat() {
+        Ok(st) => {
+            report.line(format!("\tinode: {} mode: {:o} rdev: {:#x}", st.st_ino, st.st_mode, st.st_rdev));
+            report.set("rdev", st.st_rdev);
+        }
+        Err(e) => report.error(format!("failed to stat: {e}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::Format;
+    use crate::testdir::TestDir;
+    use crate::FileMetadata;
+    use std::fs::{self, File};
+
+    #[tokio::test]
+    async fn a_file_that_fits_exactly_is_complete() -> anyhow::Result<()> {
+        let dir = TestDir::new("printer");
+        // four 4 byte characters fill head_len, a fifth is more than fits
+        let opts = PreviewOpts { len: 4, lines: 8 };
+        for (chars, truncated) in [(4, false), (5, true)] {
+            let path = dir.join(format!("f{chars}"));
+            fs::write(&path, "\u{1f980}".repeat(chars))?;
+            let msg = Msg::new(0, FileMetadata::lstat(&path)?, File::open(&path)?);
+            let report = msg.report.clone();
+            preview(&msg, &opts).await?;
+            assert!(report.render(0, Format::Json).contains(&format!("\"truncated\":{truncated}")), "{chars}");
+        }
+        Ok(())
+    }
+}
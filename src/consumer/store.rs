@@ -0,0 +1,246 @@
+use super::beneath::Beneath;
+use super::guard::{self, Guard};
+use super::{relative, Consumer, Outcome};
+use crate::hash::{self, Algorithm, Digest};
+use crate::{copy, FileType, Msg};
+use async_trait::async_trait;
+use nix::errno::Errno;
+use nix::fcntl::{openat, renameat, AtFlags, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{linkat, unlinkat, UnlinkatFlags};
+use std::ffi::OsStr;
+use std::fs::{File, Permissions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::task;
+
+/// content addressed storage, each distinct content is kept once
+///
+/// ```text
+/// DIR/<algo>/<hex>   the content, read-only
+/// DIR/tree/<path>    hard links to the objects, one per sender path
+/// DIR/index          <algo>:<hex> <tab> <sender path>, appended per file
+/// ```
+///
+/// a backslash, tab, carriage return or newline in an index path is written
+/// as `\\`, `\t`, `\r` or `\n`
+///
+/// the file is hashed once, or not at all after a hasher in the chain, and only
+/// copied when there is no object for it yet
+pub struct Store {
+    root: PathBuf,
+    algorithm: Algorithm,
+    /// hash an existing object again before it is reused, rather than trust its name
+    verify: bool,
+}
+
+impl Store {
+    pub fn new<P: AsRef<Path>>(root: P, algorithm: Algorithm) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            algorithm,
+            verify: false,
+        }
+    }
+
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stored {
+    New,
+    Duplicate,
+    /// the object that was there didn't match its name
+    Repaired,
+}
+
+#[async_trait]
+impl Consumer for Store {
+    async fn handle(&self, msg: Msg) -> anyhow::Result<Outcome> {
+        if msg.metadata.file_type != FileType::RegularFile {
+            return Ok(Outcome::Pass(Box::new(msg)));
+        }
+        let (root, algorithm, verify) = (self.root.clone(), self.algorithm, self.verify);
+        let path = msg.metadata.path.clone();
+        let file = msg.file.try_clone()?;
+        let guard = msg.guard.clone();
+        // a hasher earlier in the chain already read the file
+        let known = msg.digests.iter().find(|d| d.algorithm == algorithm).cloned();
+        // syscalls must be made in blocking context
+        let stored = task::spawn_blocking(move || {
+            let digest = match known {
+                Some(digest) => digest,
+                None => hash::digest_file(&file, algorithm)?,
+            };
+            // the digest is the file's only if it didn't change while it was hashed
+            guard::unchanged(guard.as_deref())?;
+            store(&root, &file, &path, digest.clone(), verify, guard.as_deref()).map(|stored| (digest, stored))
+        })
+        .await?;
+        match stored {
+            Ok((digest, stored)) => {
+                let note = match stored {
+                    Stored::New => "",
+                    Stored::Duplicate => " (duplicate)",
+                    Stored::Repaired => " (replaced a damaged object)",
+                };
+                msg.report.line(format!("\tstored: {digest}{note}"));
+                msg.report.set("stored", serde_json::json!({"digest": digest, "duplicate": stored == Stored::Duplicate}));
+            }
+            Err(e) => msg.report.error(format!("failed to store: {e}")),
+        }
+        Ok(Outcome::Pass(Box::new(msg)))
+    }
+}
+
+// the object is named by the digest of the file, a copy is only made when there
+// is no object for it yet and isn't kept if the sender changed the file meanwhile
+fn store(root: &Path, file: &File, path: &str, digest: Digest, verify: bool, guard: Option<&Guard>) -> anyhow::Result<Stored> {
+    let dst = Beneath::open(root)?;
+    let objects = dst.dir(Path::new(digest.algorithm.name()))?;
+    let stored = match existing(&objects, &digest, verify)? {
+        Some(true) => Stored::Duplicate,
+        found => {
+            write_object(&objects, file, &digest, guard)?;
+            if found.is_some() { Stored::Repaired } else { Stored::New }
+        }
+    };
+
+    let linked = Path::new("tree").join(relative(path)?);
+    let (tree, name) = dst.parent(&linked)?;
+    let tmp = temp_name();
+    linkat(Some(objects.as_raw_fd()), digest.hex.as_str(), Some(tree.as_raw_fd()), tmp.as_str(), AtFlags::empty())?;
+    if let Err(e) = renameat(Some(tree.as_raw_fd()), tmp.as_str(), Some(tree.as_raw_fd()), name) {
+        let _ = unlinkat(Some(tree.as_raw_fd()), tmp.as_str(), UnlinkatFlags::NoRemoveDir);
+        return Err(e.into());
+    }
+
+    // one write per line, appends from concurrent writers don't interleave
+    let flags = OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut index = File::options().create(true).append(true).custom_flags(flags.bits()).open(root.join("index"))?;
+    index.write_all(format!("{digest}\t{}\n", escape(path)).as_bytes())?;
+    Ok(stored)
+}
+
+// copied to a temp name and renamed into place, once the guard says the file is still what was hashed
+fn write_object(objects: &File, file: &File, digest: &Digest, guard: Option<&Guard>) -> anyhow::Result<()> {
+    let tmp = temp_name();
+    let out = create(objects, OsStr::new(&tmp))?;
+    let res = copy::copy_data(file, &out).map_err(anyhow::Error::from).and_then(|_| {
+        out.set_permissions(Permissions::from_mode(0o444))?;
+        out.sync_all()?;
+        guard::unchanged(guard)?;
+        Ok(renameat(Some(objects.as_raw_fd()), tmp.as_str(), Some(objects.as_raw_fd()), digest.hex.as_str())?)
+    });
+    if res.is_err() {
+        let _ = unlinkat(Some(objects.as_raw_fd()), tmp.as_str(), UnlinkatFlags::NoRemoveDir);
+    }
+    res
+}
+
+// whether there is a good object for digest, None if there is none at all.
+// it is taken at its name, unless verify has it hashed again
+fn existing(objects: &File, digest: &Digest, verify: bool) -> anyhow::Result<Option<bool>> {
+    let access = if verify { OFlag::O_RDONLY } else { OFlag::O_PATH };
+    match openat(Some(objects.as_raw_fd()), digest.hex.as_str(), access | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) if verify => {
+            let object = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+            Ok(Some(hash::digest_file(&object, digest.algorithm)? == *digest))
+        }
+        Ok(fd) => {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+            Ok(Some(true))
+        }
+        Err(Errno::ENOENT) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn create(dir: &File, name: &OsStr) -> anyhow::Result<File> {
+    let flags = OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let fd = openat(Some(dir.as_raw_fd()), name, flags, Mode::from_bits_truncate(0o600))?;
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+// unique within the process, files are stored concurrently with --jobs
+fn temp_name() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!(".{}.{}.tmp", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// one path per line, whatever the sender named its files
+fn escape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn duplicates_are_stored_once() -> anyhow::Result<()> {
+        let dir = TestDir::new("store");
+        let root = dir.join("store");
+        fs::write(dir.join("a"), "same")?;
+        fs::write(dir.join("b"), "same")?;
+        for name in ["a", "b"] {
+            let file = File::open(dir.join(name))?;
+            let digest = hash::digest_file(&file, Algorithm::Blake3)?;
+            let stored = store(&root, &file, &format!("/src/{name}"), digest, false, None)?;
+            assert_eq!(stored, if name == "a" { Stored::New } else { Stored::Duplicate });
+        }
+
+        let objects: Vec<_> = fs::read_dir(root.join("blake3"))?.collect::<Result<_, _>>()?;
+        assert_eq!(objects.len(), 1);
+        assert_eq!(fs::read_to_string(objects[0].path())?, "same");
+        assert_eq!(fs::metadata(root.join("tree/src/a"))?.ino(), fs::metadata(root.join("tree/src/b"))?.ino());
+        assert_eq!(fs::read_to_string(root.join("index"))?.lines().count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_objects_are_replaced_and_paths_escaped() -> anyhow::Result<()> {
+        let dir = TestDir::new("store-damaged");
+        let root = dir.join("store");
+        fs::write(dir.join("a"), "content")?;
+        let file = File::open(dir.join("a"))?;
+        let digest = hash::digest_file(&file, Algorithm::Sha256)?;
+        store(&root, &file, "/a", digest.clone(), false, None)?;
+
+        // someone else's bytes under the right name
+        let object = root.join("sha256").join(&digest.hex);
+        fs::set_permissions(&object, Permissions::from_mode(0o644))?;
+        fs::write(&object, "tampered")?;
+        // taken at its name unless verified
+        assert_eq!(store(&root, &file, "/c", digest.clone(), false, None)?, Stored::Duplicate);
+        assert_eq!(fs::read_to_string(&object)?, "tampered");
+        let odd = "/b\twith\ttabs\nand a newline";
+        assert_eq!(store(&root, &file, odd, digest.clone(), true, None)?, Stored::Repaired);
+        assert_eq!(fs::read_to_string(&object)?, "content");
+
+        let index = fs::read_to_string(root.join("index"))?;
+        assert_eq!(index.lines().count(), 3);
+        assert!(index.ends_with(&format!("{digest}\t/b\\twith\\ttabs\\nand a newline\n")));
+        assert_eq!(escape("a\\b"), "a\\\\b");
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+// kernel side copies between fds
+//
+// every call passes an explicit source offset, so the offset of a received
+// fd, shared with the sender, is never moved
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// shares the source's extents, nothing is copied
+    Reflink,
+    CopyFileRange,
+    Sendfile,
+    /// plain reads and writes
+    ReadWrite,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::Reflink => "reflink",
+            Method::CopyFileRange => "copy_file_range",
+            Method::Sendfile => "sendfile",
+            Method::ReadWrite => "read/write",
+        })
+    }
+}
+
+const CHUNK: usize = 1 << 30;
+
+/// copy all of src into dst, which should be empty, returning how it was done
+pub fn copy_data(src: &File, dst: &File) -> io::Result<Method> {
+    // only on one filesystem, and only if it does reflinks (btrfs, xfs)
+    match reflink(src, dst) {
+        Ok(()) => return Ok(Method::Reflink),
+        Err(e) if !fallback(&e) => return Err(e),
+        Err(_) => {}
+    }
+    match copy_file_range(src, dst) {
+        Ok(()) => return Ok(Method::CopyFileRange),
+        // across filesystems on older kernels, or a filesystem that doesn't do it at all
+        Err(e) if !fallback(&e) => return Err(e),
+        Err(_) => {}
+    }
+    match sendfile(src, dst) {
+        Ok(()) => return Ok(Method::Sendfile),
+        Err(e) if !fallback(&e) => return Err(e),
+        Err(_) => {}
+    }
+    read_write(src, dst)?;
+    Ok(Method::ReadWrite)
+}
+
+// the errors that mean try another way rather than give up
+fn fallback(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EXDEV | libc::ENOSYS | libc::EINVAL | libc::EOPNOTSUPP | libc::ENOTTY | libc::EPERM)
+    )
+}
+
+/// share the whole of src with dst, which must be a regular file on the same filesystem
+pub fn reflink(src: &File, dst: &File) -> io::Result<()> {
+    let res = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// both stop at eof rather than at the size in the metadata, the file may have changed since.
+// a failure part way leaves dst empty again for the next method to start over
+fn copy_file_range(src: &File, dst: &File) -> io::Result<()> {
+    let mut off_in: libc::loff_t = 0;
+    let mut off_out: libc::loff_t = 0;
+    loop {
+        let n = unsafe {
+            libc::copy_file_range(src.as_raw_fd(), &mut off_in, dst.as_raw_fd(), &mut off_out, CHUNK, 0)
+        };
+        if !advance(n, dst)? {
+            return Ok(());
+        }
+    }
+}
+
+fn sendfile(src: &File, dst: &File) -> io::Result<()> {
+    let mut offset: libc::off_t = 0;
+    loop {
+        let n = unsafe { libc::sendfile(dst.as_raw_fd(), src.as_raw_fd(), &mut offset, CHUNK) };
+        if !advance(n, dst)? {
+            return Ok(());
+        }
+    }
+}
+
+// whether there may be more to copy
+fn advance(n: isize, dst: &File) -> io::Result<bool> {
+    if n >= 0 {
+        return Ok(n > 0);
+    }
+    let e = io::Error::last_os_error();
+    if e.kind() == io::ErrorKind::Interrupted {
+        return Ok(true);
+    }
+    dst.set_len(0)?;
+    Err(e)
+}
+
+fn read_write(src: &File, dst: &File) -> io::Result<()> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = match src.read_at(&mut buf, offset) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dst.write_all_at(&buf[..n], offset)?;
+        offset += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::io::Read;
+
+    #[test]
+    fn copies_leave_the_source_offset_alone() -> io::Result<()> {
+        let dir = TestDir::new("copy");
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        std::fs::write(dir.join("src"), &data)?;
+        let mut src = File::open(dir.join("src"))?;
+
+        let copy = |name: &str, f: fn(&File, &File) -> io::Result<()>| -> io::Result<()> {
+            let dst = File::options().read(true).write(true).create_new(true).open(dir.join(name))?;
+            f(&src, &dst)?;
+            assert_eq!(std::fs::read(dir.join(name))?, data, "{name}");
+            Ok(())
+        };
+        copy("a", |s, d| copy_data(s, d).map(drop))?;
+        copy("b", sendfile)?;
+        copy("c", read_write)?;
+
+        let mut head = [0; 4];
+        src.read_exact(&mut head)?;
+        assert_eq!(head, [0, 1, 2, 3]);
+        Ok(())
+    }
+}
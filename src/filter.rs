@@ -0,0 +1,247 @@
+// entry filters for the tx scanner
+//
+// everything here works from the path and lstat metadata alone, so an
+// entry that is filtered out is never opened and never uses up an fd
+
+use crate::mime::Detectors;
+use crate::FileType;
+use anyhow::{bail, Context};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct FilterOpts {
+    /// only send entries matching this glob, relative to the source dir (repeatable)
+    #[clap(long)]
+    pub include: Vec<String>,
+    /// skip entries matching this glob, relative to the source dir, excluded dirs are not walked (repeatable)
+    #[clap(long)]
+    pub exclude: Vec<String>,
+    /// skip entries matched by .gitignore and .ignore files
+    #[clap(long)]
+    pub gitignore: bool,
+    /// only send entries at least this big, eg 10k or 2M
+    #[clap(long, value_parser = parse_size)]
+    pub min_size: Option<u64>,
+    /// only send entries at most this big, eg 10k or 2M
+    #[clap(long, value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// only send entries modified within this long, eg 30m or 2d, or after this file was
+    #[clap(long, value_parser = parse_newer_than)]
+    pub newer_than: Option<SystemTime>,
+    /// only send entries of these types: file, dir, symlink, block, char, fifo, socket
+    #[clap(long = "type", value_delimiter = ',')]
+    pub types: Vec<FileType>,
+    /// only send entries with these mime types, a trailing /* matches the whole family
+    /// judged by name, the sent metadata has the type sniffed from the content, which can differ
+    #[clap(long, value_delimiter = ',')]
+    pub mime: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// send it, and walk it if it is a dir
+    Select,
+    /// don't send it, but still walk it
+    Skip,
+    /// don't send it and don't walk it
+    Prune,
+}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    root: PathBuf,
+    opts: FilterOpts,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    detectors: Detectors,
+}
+
+impl Filter {
+    pub fn new<P: AsRef<Path>>(root: P, opts: FilterOpts) -> anyhow::Result<Self> {
+        let include = if opts.include.is_empty() { None } else { Some(globs(&opts.include)?) };
+        let exclude = globs(&opts.exclude)?;
+        Ok(Self {
+            root: root.as_ref().to_path_buf(),
+            opts,
+            include,
+            exclude,
+            detectors: Detectors::default(),
+        })
+    }
+
+    /// detect mime types with these instead of the built in table
+    pub fn with_detectors(mut self, detectors: Detectors) -> Self {
+        self.detectors = detectors;
+        self
+    }
+
+    pub fn gitignore(&self) -> bool {
+        self.opts.gitignore
+    }
+
+    pub fn check(&self, path: &Path, metadata: &Metadata, ignores: &[Arc<Gitignore>]) -> Verdict {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        if self.exclude.is_match(rel) || ignored(ignores, path, metadata.is_dir()) {
+            return Verdict::Prune;
+        }
+        if self.selects(rel, metadata) {
+            Verdict::Select
+        } else {
+            Verdict::Skip
+        }
+    }
+
+    fn selects(&self, rel: &Path, metadata: &Metadata) -> bool {
+        let opts = &self.opts;
+        if self.include.as_ref().is_some_and(|include| !include.is_match(rel)) {
+            return false;
+        }
+        if opts.min_size.is_some_and(|min| metadata.len() < min) || opts.max_size.is_some_and(|max| metadata.len() > max) {
+            return false;
+        }
+        if opts.newer_than.is_some_and(|t| metadata.modified().map_or(true, |m| m <= t)) {
+            return false;
+        }
+        if !opts.types.is_empty() && !opts.types.contains(&FileType::from(metadata)) {
+            return false;
+        }
+        // by name only, sniffing would mean opening every entry
+        if !opts.mime.is_empty() {
+            let mime = self.detectors.detect_by_name(rel);
+            if !opts.mime.iter().any(|m| mime_matches(m, &mime)) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// matchers for the .gitignore and .ignore files in dir, if there are any
+pub fn load_ignores(dir: &Path) -> Option<Arc<Gitignore>> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    for name in [".gitignore", ".ignore"] {
+        let file = dir.join(name);
+        if file.is_file() {
+            found = true;
+            if let Some(e) = builder.add(&file) {
+                println!("tx: problem in {}: {e}", file.display());
+            }
+        }
+    }
+    if !found {
+        return None;
+    }
+    match builder.build() {
+        Ok(gi) => Some(Arc::new(gi)),
+        Err(e) => {
+            println!("tx: ignoring {}: {e}", dir.display());
+            None
+        }
+    }
+}
+
+// the deepest file with an opinion wins, so a child can re-include
+fn ignored(ignores: &[Arc<Gitignore>], path: &Path, is_dir: bool) -> bool {
+    for gi in ignores.iter().rev() {
+        match gi.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+fn globs(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for p in patterns {
+        builder.add(Glob::new(p).with_context(|| format!("bad glob {p}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(family) => mime.split('/').next() == Some(family),
+        None => pattern == mime,
+    }
+}
+
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits.parse().with_context(|| format!("bad size {s}"))?;
+    let scale = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => bail!("bad size unit in {s}, expected k, M or G"),
+    };
+    n.checked_mul(scale).with_context(|| format!("size {s} is too big"))
+}
+
+// either a reference file or an age like 90s, 30m, 2h or 7d
+pub fn parse_newer_than(s: &str) -> anyhow::Result<SystemTime> {
+    let path = Path::new(s);
+    if path.exists() {
+        return Ok(path.metadata()?.modified()?);
+    }
+    let (digits, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits.parse().with_context(|| format!("{s} is neither a file nor an age"))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => bail!("bad age unit in {s}, expected s, m, h or d"),
+    };
+    let secs = n.checked_mul(scale).with_context(|| format!("age {s} is too long"))?;
+    SystemTime::now().checked_sub(Duration::from_secs(secs)).with_context(|| format!("age {s} is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_and_mimes() -> anyhow::Result<()> {
+        assert_eq!(parse_size("12")?, 12);
+        assert_eq!(parse_size("2k")?, 2048);
+        assert_eq!(parse_size("1M")?, 1 << 20);
+        assert!(parse_size("1x").is_err());
+        assert!(parse_size("18446744073709551615k").is_err());
+        assert!(parse_newer_than("2d").is_ok());
+        assert!(parse_newer_than("18446744073709551615d").is_err());
+        assert!(parse_newer_than("18446744073709551615s").is_err());
+        assert!(mime_matches("text/*", "text/x-rust"));
+        assert!(mime_matches("image/png", "image/png"));
+        assert!(!mime_matches("text/*", "application/json"));
+        Ok(())
+    }
+
+    #[test]
+    fn excludes_prune_and_includes_skip() -> anyhow::Result<()> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let filter = Filter::new(
+            root,
+            FilterOpts {
+                include: vec!["**/*.rs".into()],
+                exclude: vec!["target".into()],
+                ..Default::default()
+            },
+        )?;
+        let dir = root.join("src").metadata()?;
+        let file = root.join("src/lib.rs").metadata()?;
+        assert_eq!(filter.check(&root.join("target"), &dir, &[]), Verdict::Prune);
+        assert_eq!(filter.check(&root.join("src"), &dir, &[]), Verdict::Skip);
+        assert_eq!(filter.check(&root.join("src/lib.rs"), &file, &[]), Verdict::Select);
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+// wire format shared by tx and rx
+//
+// a frame is a fixed header of three native endian u16s (kind, size1, size2) followed by
+// two payloads of those sizes. any fds ride along with the header as SCM_RIGHTS.
+
+use crate::FileMetadata;
+use anyhow::{bail, Context};
+use nix::cmsg_space;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use nix::sys::socket::{recv as recv_bytes, recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags, UnixAddr};
+use serde::{Deserialize, Serialize};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::io::RawFd;
+
+/// metadata in payload 1, fd attached when there is one
+pub const FILE: u16 = 1;
+/// bincode OpenRequest in payload 1
+pub const OPEN: u16 = 2;
+/// utf8 error message in payload 1
+pub const ERROR: u16 = 3;
+
+/// the most either payload of a frame can hold
+pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+/// ask a serving tx to open a path relative to its root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenRequest {
+    pub path: String,
+    pub write: bool,
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub kind: u16,
+    pub d1: Vec<u8>,
+    pub d2: Vec<u8>,
+    pub fds: Vec<OwnedFd>,
+}
+
+/// bincode metadata for a FILE frame, the xattrs are dropped when they won't fit
+/// None when it doesn't fit even without them
+pub fn metadata(meta: &mut FileMetadata) -> anyhow::Result<Option<Vec<u8>>> {
+    let serialized = bincode::serialize(meta)?;
+    if serialized.len() <= MAX_PAYLOAD {
+        return Ok(Some(serialized));
+    }
+    if meta.xattrs.is_empty() {
+        return Ok(None);
+    }
+    meta.xattrs.clear();
+    let serialized = bincode::serialize(meta)?;
+    Ok((serialized.len() <= MAX_PAYLOAD).then_some(serialized))
+}
+
+// send a frame, requires blocking context
+pub fn send(fd: RawFd, kind: u16, d1: &[u8], d2: &[u8], fds: &[RawFd]) -> anyhow::Result<()> {
+    if d1.len() > MAX_PAYLOAD || d2.len() > MAX_PAYLOAD {
+        bail!("frame payload too large: {} + {}", d1.len(), d2.len());
+    }
+    let t = kind.to_ne_bytes();
+    let size1 = (d1.len() as u16).to_ne_bytes();
+    let size2 = (d2.len() as u16).to_ne_bytes();
+    let iov = [IoSlice::new(&t), IoSlice::new(&size1), IoSlice::new(&size2), IoSlice::new(d1), IoSlice::new(d2)];
+
+    let control_messages = if fds.is_empty() {
+        vec![]
+    } else {
+        vec![ControlMessage::ScmRights(fds)]
+    };
+
+    sendmsg(fd, &iov, &control_messages, MsgFlags::empty(), None::<&UnixAddr>).context("failed to send frame")?;
+    Ok(())
+}
+
+// receive a frame, Ok(None) when the peer has hung up between frames
+// EAGAIN only ever comes before any of a frame has been read, so it can be retried
+// fds in the frame are owned by the caller from here on
+pub fn recv(fd: RawFd) -> nix::Result<Option<Frame>> {
+    let mut cmsg_buf = cmsg_space!(RawFd);
+    let mut header = HeaderData::default();
+
+    let (read, fds) = {
+        let mut iov = [IoSliceMut::new(&mut header.0)];
+        let res = recvmsg::<()>(fd, &mut iov, Some(&mut cmsg_buf), MsgFlags::empty())?;
+        if res.bytes == 0 {
+            return Ok(None);
+        }
+        let mut fds: Vec<OwnedFd> = vec![];
+        for cmsg in res.cmsgs()? {
+            match cmsg {
+                // take ownership of the fds
+                ControlMessageOwned::ScmRights(raw) => fds.extend(raw.into_iter().map(|f| unsafe { OwnedFd::from_raw_fd(f) })),
+                // stdout may be json lines or an archive
+                other => eprintln!("\tother ctrl-msg: {other:?}"),
+            }
+        }
+        (res.bytes, fds)
+    };
+
+    // a stream socket may hand over the rest of the frame in pieces
+    read_exact(fd, &mut header.0[read..])?;
+    let kind = header.t();
+    let mut payload: PayloadData = header.into();
+    read_exact(fd, &mut payload.d1)?;
+    read_exact(fd, &mut payload.d2)?;
+
+    Ok(Some(Frame {
+        kind,
+        d1: payload.d1,
+        d2: payload.d2,
+        fds,
+    }))
+}
+
+// all of buf once a frame has begun, a short read is never taken for a whole one.
+// a non-blocking socket is polled until the rest arrives, the sender is already sending it,
+// and a peer that hangs up part way through a frame is a protocol error rather than the end
+fn read_exact(fd: RawFd, mut buf: &mut [u8]) -> nix::Result<()> {
+    while !buf.is_empty() {
+        match recv_bytes(fd, buf, MsgFlags::empty()) {
+            Ok(0) => return Err(Errno::EPROTO),
+            Ok(n) => buf = &mut buf[n..],
+            Err(Errno::EAGAIN) => {
+                let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+                poll(&mut [PollFd::new(fd, PollFlags::POLLIN)], PollTimeout::NONE)?;
+            }
+            Err(Errno::EINTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// kind, size1 and size2
+#[derive(Default)]
+struct HeaderData([u8; 6]);
+
+impl HeaderData {
+    fn t(&self) -> u16 {
+        u16::from_ne_bytes([self.0[0], self.0[1]])
+    }
+    fn s1(&self) -> u16 {
+        u16::from_ne_bytes([self.0[2], self.0[3]])
+    }
+    fn s2(&self) -> u16 {
+        u16::from_ne_bytes([self.0[4], self.0[5]])
+    }
+}
+
+struct PayloadData {
+    d1: Vec<u8>,
+    d2: Vec<u8>,
+}
+
+impl PayloadData {
+    fn new(s1: u16, s2: u16) -> Self {
+        PayloadData {
+            d1: vec![0; s1 as usize],
+            d2: vec![0; s2 as usize],
+        }
+    }
+}
+
+impl From<HeaderData> for PayloadData {
+    fn from(hd: HeaderData) -> Self {
+        PayloadData::new(hd.s1(), hd.s2())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::xattr::Xattr;
+    use std::fs::File;
+    use std::io::{Read, Seek, Write};
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn roundtrip_with_fd() -> anyhow::Result<()> {
+        let (a, b) = UnixStream::pair()?;
+        let dir = TestDir::new("frame");
+        let mut file = File::options().read(true).write(true).create(true).truncate(true).open(dir.join("f"))?;
+        file.write_all(b"hello")?;
+        file.rewind()?;
+
+        send(a.as_raw_fd(), FILE, b"one", b"two", &[file.as_raw_fd()])?;
+        let frame = recv(b.as_raw_fd())?.expect("frame");
+        assert_eq!(frame.kind, FILE);
+        assert_eq!(frame.d1, b"one");
+        assert_eq!(frame.d2, b"two");
+        assert_eq!(frame.fds.len(), 1);
+
+        let mut contents = String::new();
+        File::from(frame.fds.into_iter().next().unwrap()).read_to_string(&mut contents)?;
+        assert_eq!(contents, "hello");
+
+        drop(a);
+        assert!(recv(b.as_raw_fd())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_xattrs_are_dropped() -> anyhow::Result<()> {
+        let dir = TestDir::new("frame-xattrs");
+        let path = dir.join("f");
+        let file = File::create(&path)?;
+        let mut meta = FileMetadata::from_fd(&path, &file)?;
+        meta.xattrs.push(Xattr { name: "user.small".into(), value: b"kept".to_vec() });
+        assert!(metadata(&mut meta)?.is_some());
+        assert_eq!(meta.xattrs.len(), 1);
+
+        meta.xattrs.push(Xattr { name: "user.big".into(), value: vec![0; 70000] });
+        let serialized = metadata(&mut meta)?.expect("fits without xattrs");
+        assert!(meta.xattrs.is_empty());
+        let back: FileMetadata = bincode::deserialize(&serialized)?;
+        assert!(back.xattrs.is_empty());
+        assert_eq!(back.path, meta.path);
+
+        meta.path = "p".repeat(70000);
+        assert!(metadata(&mut meta)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn frames_arriving_in_pieces_are_waited_for() -> anyhow::Result<()> {
+        let (mut a, b) = UnixStream::pair()?;
+        b.set_nonblocking(true)?;
+        let mut bytes = vec![];
+        bytes.extend(FILE.to_ne_bytes());
+        bytes.extend(3u16.to_ne_bytes());
+        bytes.extend(2u16.to_ne_bytes());
+        bytes.extend(b"onetw");
+        a.write_all(&bytes[..4])?;
+        let writer = std::thread::spawn(move || -> std::io::Result<UnixStream> {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            a.write_all(&bytes[4..8])?;
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            a.write_all(&bytes[8..])?;
+            // then hang up part way through the next frame
+            a.write_all(&FILE.to_ne_bytes())?;
+            a.write_all(&1u16.to_ne_bytes())?;
+            a.write_all(&0u16.to_ne_bytes())?;
+            Ok(a)
+        });
+        let frame = recv(b.as_raw_fd())?.expect("frame");
+        assert_eq!((frame.kind, &frame.d1[..], &frame.d2[..]), (FILE, &b"one"[..], &b"tw"[..]));
+        drop(writer.join().unwrap()?);
+        assert_eq!(recv(b.as_raw_fd()).err(), Some(Errno::EPROTO));
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+// handles for received fds that aren't plain files
+//
+// everything is resolved relative to the fd, a consumer walking a dir it was
+// handed never needs to know where that dir lives
+
+use crate::FileType;
+use anyhow::bail;
+use nix::dir::{Dir, Type};
+use nix::fcntl::{open, openat, readlinkat, AtFlags, OFlag};
+use nix::sys::stat::{fstatat, FileStat, Mode};
+use std::fs::File;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+/// a received directory fd
+#[derive(Debug)]
+pub struct DirHandle {
+    fd: OwnedFd,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+impl DirHandle {
+    pub fn new<F: Into<OwnedFd>>(fd: F) -> Self {
+        Self { fd: fd.into() }
+    }
+
+    // reads through a fresh open of ".", so the offset of the received fd is left alone
+    pub fn entries(&self) -> anyhow::Result<Vec<DirEntry>> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+        let mut dir = Dir::openat(Some(self.fd.as_raw_fd()), ".", flags, Mode::empty())?;
+        let mut entries = vec![];
+        for e in dir.iter() {
+            let e = e?;
+            let name = e.file_name().to_string_lossy().to_string();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let file_type = match e.file_type() {
+                Some(Type::File) => FileType::RegularFile,
+                Some(Type::Directory) => FileType::Directory,
+                Some(Type::Symlink) => FileType::SymbolicLink,
+                Some(Type::BlockDevice) => FileType::BlockDevice,
+                Some(Type::CharacterDevice) => FileType::CharacterDevice,
+                Some(Type::Fifo) => FileType::Fifo,
+                Some(Type::Socket) => FileType::Socket,
+                None => FileType::Unknown,
+            };
+            entries.push(DirEntry { name, file_type });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    /// open a child file read-only
+    pub fn open(&self, name: &str) -> anyhow::Result<File> {
+        Ok(File::from(self.openat(name, OFlag::O_RDONLY)?))
+    }
+
+    /// open a child dir
+    pub fn open_dir(&self, name: &str) -> anyhow::Result<DirHandle> {
+        Ok(DirHandle::new(self.openat(name, OFlag::O_RDONLY | OFlag::O_DIRECTORY)?))
+    }
+
+    // children are single names and symlinks are never followed, nothing resolves outside the dir
+    fn openat(&self, name: &str, flags: OFlag) -> anyhow::Result<OwnedFd> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            bail!("{name} is not an entry name");
+        }
+        let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_NOCTTY | OFlag::O_CLOEXEC;
+        let fd = openat(Some(self.fd.as_raw_fd()), name, flags, Mode::empty())?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+impl From<File> for DirHandle {
+    fn from(file: File) -> Self {
+        Self::new(file)
+    }
+}
+
+impl AsFd for DirHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// a received O_PATH fd, for entries that can't or shouldn't be opened for io
+/// like symlinks, devices, fifos and sockets
+#[derive(Debug)]
+pub struct PathHandle {
+    fd: OwnedFd,
+}
+
+impl PathHandle {
+    pub fn new<F: Into<OwnedFd>>(fd: F) -> Self {
+        Self { fd: fd.into() }
+    }
+
+    /// stat of the entry itself, a symlink is not followed
+    pub fn stat(&self) -> anyhow::Result<FileStat> {
+        let flags = AtFlags::AT_EMPTY_PATH | AtFlags::AT_SYMLINK_NOFOLLOW;
+        Ok(fstatat(Some(self.fd.as_raw_fd()), "", flags)?)
+    }
+
+    /// target of a symlink
+    pub fn read_link(&self) -> anyhow::Result<String> {
+        Ok(readlinkat(Some(self.fd.as_raw_fd()), "")?.to_string_lossy().to_string())
+    }
+
+    /// open the entry for real through /proc/self/fd
+    /// this fails for symlinks, and opening a fifo for reading blocks until it has a writer
+    pub fn reopen(&self, flags: OFlag) -> anyhow::Result<File> {
+        let proc_path = format!("/proc/self/fd/{}", self.fd.as_raw_fd());
+        let fd = open(proc_path.as_str(), flags | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, Mode::empty())?;
+        Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
+
+impl From<File> for PathHandle {
+    fn from(file: File) -> Self {
+        Self::new(file)
+    }
+}
+
+impl AsFd for PathHandle {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use nix::sys::stat::SFlag;
+    use std::fs;
+    use std::io::Read;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    #[test]
+    fn dir_and_path_handles() -> anyhow::Result<()> {
+        let root = TestDir::new("handle");
+        fs::create_dir_all(root.join("sub"))?;
+        fs::write(root.join("sub/f"), "hi")?;
+        std::os::unix::fs::symlink("sub/f", root.join("link"))?;
+
+        let dir = DirHandle::new(File::open(&*root)?);
+        let names: Vec<_> = dir.entries()?.into_iter().map(|e| (e.name, e.file_type)).collect();
+        assert_eq!(names, [("link".to_string(), FileType::SymbolicLink), ("sub".to_string(), FileType::Directory)]);
+        let mut contents = String::new();
+        dir.open_dir("sub")?.open("f")?.read_to_string(&mut contents)?;
+        assert_eq!(contents, "hi");
+        assert!(dir.open("link").is_err());
+        assert!(dir.open("..").is_err());
+        assert!(dir.open("sub/f").is_err());
+
+        let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW;
+        let link = PathHandle::from(File::options().read(true).custom_flags(flags.bits()).open(root.join("link"))?);
+        assert_eq!(link.read_link()?, "sub/f");
+        assert_eq!(SFlag::from_bits_truncate(link.stat()?.st_mode) & SFlag::S_IFMT, SFlag::S_IFLNK);
+
+        let meta = crate::FileMetadata::lstat(&root.join("link"))?;
+        assert_eq!(meta.file_type, FileType::SymbolicLink);
+        assert_eq!(meta.symlink_target.as_deref(), Some("sub/f"));
+        assert!(!meta.dangling);
+        fs::remove_file(root.join("sub/f"))?;
+        assert!(crate::FileMetadata::lstat(&root.join("link"))?.dangling);
+        Ok(())
+    }
+}
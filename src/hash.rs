@@ -0,0 +1,254 @@
+// content digests
+//
+// the sender hashes with positional reads, the fd it hands over keeps its
+// offset at 0 for the receiver. a MultiHasher feeds several algorithms from
+// one pass over the content.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Sha1,
+    Sha512,
+    Blake3,
+    /// xxh3, 64 bit
+    Xxh3,
+    Crc32c,
+}
+
+impl Algorithm {
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Sha256 => Box::new(DigestHasher(sha2::Sha256::default())),
+            Algorithm::Sha1 => Box::new(DigestHasher(sha1::Sha1::default())),
+            Algorithm::Sha512 => Box::new(DigestHasher(sha2::Sha512::default())),
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            Algorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+            Algorithm::Crc32c => Box::new(Crc32c(0)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Xxh3 => "xxh3",
+            Algorithm::Crc32c => "crc32c",
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        <Self as clap::ValueEnum>::from_str(s, true).map_err(|_| anyhow::anyhow!("unknown hash algorithm {s}"))
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// a finished hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Digest {
+    pub algorithm: Algorithm,
+    /// lowercase hex
+    pub hex: String,
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+    /// a lot at once, eg a whole mapped file, hashers that can split it across threads do
+    fn update_large(&mut self, data: &[u8]) {
+        self.update(data);
+    }
+    /// lowercase hex of everything so far
+    fn finish(&self) -> String;
+}
+
+struct DigestHasher<D>(D);
+
+impl<D: sha2::Digest + Clone + Send> Hasher for DigestHasher<D> {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finish(&self) -> String {
+        hex(&self.0.clone().finalize())
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    // blake3 is a tree hash, the subtrees are hashed on the rayon pool
+    fn update_large(&mut self, data: &[u8]) {
+        self.update_rayon(data);
+    }
+
+    fn finish(&self) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+impl Hasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finish(&self) -> String {
+        format!("{:016x}", self.digest())
+    }
+}
+
+struct Crc32c(u32);
+
+impl Hasher for Crc32c {
+    fn update(&mut self, data: &[u8]) {
+        self.0 = crc32c::crc32c_append(self.0, data);
+    }
+
+    fn finish(&self) -> String {
+        format!("{:08x}", self.0)
+    }
+}
+
+/// several algorithms fed from the same reads
+pub struct MultiHasher(Vec<(Algorithm, Box<dyn Hasher>)>);
+
+impl MultiHasher {
+    // duplicates are only hashed once
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let mut algorithms = algorithms.to_vec();
+        algorithms.sort();
+        algorithms.dedup();
+        Self(algorithms.into_iter().map(|a| (a, a.hasher())).collect())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, h) in &mut self.0 {
+            h.update(data);
+        }
+    }
+
+    /// everything in one go, each algorithm on its own thread
+    pub fn update_large(&mut self, data: &[u8]) {
+        if let [(_, h)] = self.0.as_mut_slice() {
+            return h.update_large(data);
+        }
+        std::thread::scope(|s| {
+            for (_, h) in &mut self.0 {
+                s.spawn(move || h.update_large(data));
+            }
+        });
+    }
+
+    pub fn finish(&self) -> Vec<Digest> {
+        self.0
+            .iter()
+            .map(|(algorithm, h)| Digest {
+                algorithm: *algorithm,
+                hex: h.finish(),
+            })
+            .collect()
+    }
+}
+
+/// digest of the whole file, without moving the file offset
+pub fn digest_file(file: &File, algorithm: Algorithm) -> io::Result<Digest> {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; CHUNK];
+    let mut offset = 0;
+    loop {
+        let n = match file.read_at(&mut buf, offset) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..n]);
+        offset += n as u64;
+    }
+    Ok(Digest {
+        algorithm,
+        hex: hasher.finish(),
+    })
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn hashing_leaves_the_offset_alone() -> io::Result<()> {
+        let dir = TestDir::new("hash");
+        let path = dir.join("f");
+        File::create(&path)?.write_all(b"abc")?;
+        let mut file = File::open(&path)?;
+        let digest = digest_file(&file, Algorithm::Sha256)?;
+        assert_eq!(digest.hex, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "abc");
+        Ok(())
+    }
+
+    #[test]
+    fn one_pass_many_digests() {
+        let mut hasher = MultiHasher::new(&[Algorithm::Crc32c, Algorithm::Sha1, Algorithm::Crc32c, Algorithm::Blake3]);
+        hasher.update(b"1234");
+        hasher.update(b"56789");
+        let hexes: Vec<_> = hasher.finish().into_iter().map(|d| (d.algorithm, d.hex)).collect();
+        assert_eq!(hexes.len(), 3);
+        assert_eq!(hexes[0], (Algorithm::Sha1, "f7c3bc1d808e04732adf679965ccc34ca7ae3441".to_string()));
+        assert_eq!(hexes[1].0, Algorithm::Blake3);
+        assert_eq!(hexes[2], (Algorithm::Crc32c, "e3069283".to_string()));
+    }
+
+    #[test]
+    fn large_updates_match_chunked_ones() {
+        // big enough for blake3 to split it up
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i * 7) as u8).collect();
+        let all = [Algorithm::Sha256, Algorithm::Blake3, Algorithm::Xxh3];
+        let mut chunked = MultiHasher::new(&all);
+        for chunk in data.chunks(CHUNK) {
+            chunked.update(chunk);
+        }
+        let mut large = MultiHasher::new(&all);
+        large.update_large(&data);
+        assert_eq!(large.finish(), chunked.finish());
+        let mut single = MultiHasher::new(&[Algorithm::Blake3]);
+        single.update_large(&data);
+        assert_eq!(single.finish()[0].hex, blake3::hash(&data).to_hex().as_str());
+    }
+}
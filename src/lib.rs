@@ -1,11 +1,35 @@
 pub mod consumer;
+pub mod copy;
+pub mod filter;
+pub mod frame;
+pub mod handle;
+pub mod hash;
+pub mod memfd;
+pub mod mime;
+pub mod policy;
+pub mod preview;
+pub mod reader;
+pub mod report;
+pub mod scan;
+pub mod serve;
+pub mod stat;
+pub mod xattr;
 mod uds;
+#[cfg(test)]
+mod testdir;
 
+use nix::fcntl::readlinkat;
 use nix::sys::stat::{Mode, SFlag};
+use mime::{detect_mime_type, Detectors};
 use serde::{Deserialize, Serialize};
+use stat::ExtendedStat;
+use xattr::Xattr;
 use std::fs::{File, Metadata};
+use std::os::fd::AsRawFd;
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug)]
@@ -13,6 +37,35 @@ pub struct Msg {
     pub id: usize,
     pub metadata: FileMetadata,
     pub file: File,
+    /// computed by consumers earlier in the chain
+    pub digests: Vec<hash::Digest>,
+    /// what the consumers have to say about it
+    pub report: report::Report,
+    /// looked up once, for the report and the printer
+    pub seals: Vec<&'static str>,
+    /// watches content that can change, set by the chain, for consumers to check before they commit anything
+    pub guard: Option<Arc<consumer::Guard>>,
+}
+
+impl Msg {
+    pub fn new(id: usize, metadata: FileMetadata, file: File) -> Self {
+        let report = report::Report::default();
+        report.set("fd", file.as_raw_fd());
+        report.set("metadata", &metadata);
+        let seals = memfd::seal_names(&file);
+        if !seals.is_empty() {
+            report.set("seals", &seals);
+        }
+        Self {
+            id,
+            metadata,
+            file,
+            digests: vec![],
+            report,
+            seals,
+            guard: None,
+        }
+    }
 }
 
 // metadata tracking
@@ -27,9 +80,18 @@ pub struct FileMetadata {
     pub created_time: u64,
     pub is_executable: bool,
     pub mime_type: String,
+    /// where a symlink points, as stored in the link
+    pub symlink_target: Option<String>,
+    /// a symlink whose target doesn't exist
+    pub dangling: bool,
+    pub stat: ExtendedStat,
+    /// only collected on request, see FileMetadata::collect_xattrs
+    pub xattrs: Vec<Xattr>,
+    /// hash of the content when it was sent, see FileMetadata::compute_digest
+    pub digest: Option<hash::Digest>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FileType {
     RegularFile,
     Directory,
@@ -41,38 +103,110 @@ pub enum FileType {
     Unknown,
 }
 
-impl FileMetadata {
-    pub fn new(path: &Path, metadata: &Metadata) -> anyhow::Result<Self> {
-        let file_type = if metadata.is_file() {
+// only lstat metadata can describe a symlink, followed metadata describes its
+// target. the symlink check comes first so lstat of a link is never mistaken for it
+impl From<&Metadata> for FileType {
+    fn from(metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            FileType::SymbolicLink
+        } else if file_type.is_file() {
             FileType::RegularFile
-        } else if metadata.is_dir() {
+        } else if file_type.is_dir() {
             FileType::Directory
-        } else if metadata.is_symlink() {
-            FileType::SymbolicLink
         } else {
-            let _mode = Mode::from_bits_truncate(metadata.mode());
-            let file_type_bits = SFlag::from_bits_truncate(metadata.mode());
-            if file_type_bits.contains(SFlag::S_IFBLK) {
-                FileType::BlockDevice
-            } else if file_type_bits.contains(SFlag::S_IFCHR) {
-                FileType::CharacterDevice
-            } else if file_type_bits.contains(SFlag::S_IFIFO) {
-                FileType::Fifo
-            } else if file_type_bits.contains(SFlag::S_IFSOCK) {
-                FileType::Socket
-            } else {
-                FileType::Unknown
+            match SFlag::from_bits_truncate(metadata.mode()) & SFlag::S_IFMT {
+                SFlag::S_IFBLK => FileType::BlockDevice,
+                SFlag::S_IFCHR => FileType::CharacterDevice,
+                SFlag::S_IFIFO => FileType::Fifo,
+                SFlag::S_IFSOCK => FileType::Socket,
+                _ => FileType::Unknown,
             }
+        }
+    }
+}
+
+// short names used on the command line and in policy files
+impl FromStr for FileType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "file" => FileType::RegularFile,
+            "dir" => FileType::Directory,
+            "symlink" => FileType::SymbolicLink,
+            "block" => FileType::BlockDevice,
+            "char" => FileType::CharacterDevice,
+            "fifo" => FileType::Fifo,
+            "socket" => FileType::Socket,
+            _ => anyhow::bail!("unknown file type {s}"),
+        })
+    }
+}
+
+impl FileMetadata {
+    /// metadata for path itself, a symlink is described rather than followed
+    pub fn lstat(path: &Path) -> anyhow::Result<Self> {
+        Self::new(path, &path.symlink_metadata()?)
+    }
+
+    // metadata should come from lstat (symlink_metadata) of path,
+    // followed metadata describes the target instead of path
+    pub fn new(path: &Path, metadata: &Metadata) -> anyhow::Result<Self> {
+        let file_type = FileType::from(metadata);
+        let (symlink_target, dangling) = if file_type == FileType::SymbolicLink {
+            let target = std::fs::read_link(path)?.to_string_lossy().to_string();
+            (Some(target), std::fs::metadata(path).is_err())
+        } else {
+            (None, false)
         };
 
+        // statx the same way the metadata was taken, a non-symlink was either followed or needed no following
+        let stat = ExtendedStat::new(path, file_type != FileType::SymbolicLink)
+            .ok()
+            .filter(|s| s.inode == metadata.ino())
+            .unwrap_or_else(|| ExtendedStat::from(metadata));
+        Self::with_stat(path, metadata, stat, symlink_target, dangling)
+    }
+
+    /// metadata from the fd alone, which should be opened without following a symlink, eg O_PATH | O_NOFOLLOW
+    /// nothing is looked up by path, the target of a symlink isn't followed so dangling is left unset
+    pub fn from_fd(path: &Path, file: &File) -> anyhow::Result<Self> {
+        let metadata = file.metadata()?;
+        let symlink_target = if FileType::from(&metadata) == FileType::SymbolicLink {
+            Some(readlinkat(Some(file.as_raw_fd()), "")?.to_string_lossy().to_string())
+        } else {
+            None
+        };
+        let stat = ExtendedStat::from_fd(file).unwrap_or_else(|_| ExtendedStat::from(&metadata));
+        Self::with_stat(path, &metadata, stat, symlink_target, false)
+    }
+
+    /// metadata for an open file that isn't at any path, eg a memfd
+    /// name is what the receiver sees as its path
+    pub fn detached(name: &str, file: &File) -> anyhow::Result<Self> {
+        Self::from_fd(Path::new(name), file)
+    }
+
+    fn with_stat(
+        path: &Path,
+        metadata: &Metadata,
+        stat: ExtendedStat,
+        symlink_target: Option<String>,
+        dangling: bool,
+    ) -> anyhow::Result<Self> {
+        let file_type = FileType::from(metadata);
         let modified_time = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
 
-        let created_time = metadata
-            .created()
-            .unwrap_or(SystemTime::UNIX_EPOCH)
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        let created_time = match stat.btime {
+            Some(btime) => btime.secs.max(0) as u64,
+            None => metadata
+                .created()
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
 
         let permissions = metadata.permissions().mode();
         let is_executable =
@@ -88,27 +222,32 @@ impl FileMetadata {
             created_time,
             is_executable,
             mime_type,
+            symlink_target,
+            dangling,
+            stat,
+            xattrs: vec![],
+            digest: None,
         })
     }
-}
 
+    /// replace the name based mime type with one sniffed from the content
+    pub fn sniff_mime(&mut self, file: &File, detectors: &Detectors) {
+        if self.file_type == FileType::RegularFile {
+            self.mime_type = detectors.sniff(Path::new(&self.path), file);
+        }
+    }
+
+    /// hash the content of a regular file so the receiver can tell if it changed since
+    pub fn compute_digest(&mut self, file: &File, algorithm: hash::Algorithm) -> anyhow::Result<()> {
+        if self.file_type == FileType::RegularFile {
+            self.digest = Some(hash::digest_file(file, algorithm)?);
+        }
+        Ok(())
+    }
 
-fn detect_mime_type(path: &Path) -> String {
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some("txt") => "text/plain".to_string(),
-        Some("html") | Some("htm") => "text/html".to_string(),
-        Some("css") => "text/css".to_string(),
-        Some("js") => "application/javascript".to_string(),
-        Some("json") => "application/json".to_string(),
-        Some("png") => "image/png".to_string(),
-        Some("jpg") | Some("jpeg") => "image/jpeg".to_string(),
-        Some("gif") => "image/gif".to_string(),
-        Some("pdf") => "application/pdf".to_string(),
-        Some("zip") => "application/zip".to_string(),
-        Some("rs") => "text/x-rust".to_string(),
-        Some("py") => "text/x-python".to_string(),
-        Some("c") => "text/x-c".to_string(),
-        Some("cpp") | Some("cc") => "text/x-c++".to_string(),
-        _ => "application/octet-stream".to_string(),
+    /// add the xattrs and acls of path itself
+    pub fn collect_xattrs(&mut self, path: &Path) -> anyhow::Result<()> {
+        self.xattrs = xattr::list(path)?;
+        Ok(())
     }
 }
@@ -0,0 +1,86 @@
+// content that isn't on disk, sent as a sealed memfd
+//
+// the data is written into an anonymous memfd which is then sealed against
+// writes, shrinking and growing, so whatever the receiver reads is what was
+// sent. no temp files, and nothing for either side to clean up.
+
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::ffi::CString;
+use std::fs::{File, Permissions};
+use std::io::{self, Read, Seek};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+
+/// the seals that make a memfd's content immutable
+pub const IMMUTABLE: SealFlag = SealFlag::F_SEAL_WRITE.union(SealFlag::F_SEAL_SHRINK).union(SealFlag::F_SEAL_GROW);
+
+/// an empty memfd that can be sealed, name only shows up in /proc/self/fd
+pub fn create(name: &str) -> anyhow::Result<File> {
+    let name = CString::new(name)?;
+    let file = File::from(memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING)?);
+    // memfds start out 0777, the content is data not a program
+    file.set_permissions(Permissions::from_mode(0o644))?;
+    Ok(file)
+}
+
+/// make the content immutable, and the seals themselves final
+// F_SEAL_WRITE fails while anything has a writable shared mapping of it
+pub fn seal(file: &File) -> anyhow::Result<()> {
+    fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(IMMUTABLE | SealFlag::F_SEAL_SEAL))?;
+    Ok(())
+}
+
+/// the seals on file, none for anything but a memfd
+pub fn seals(file: &File) -> SealFlag {
+    fcntl(file.as_raw_fd(), FcntlArg::F_GET_SEALS).map_or(SealFlag::empty(), SealFlag::from_bits_truncate)
+}
+
+/// sealed so that its content can't change, whoever else has it open
+pub fn is_immutable(file: &File) -> bool {
+    seals(file).contains(IMMUTABLE)
+}
+
+/// eg F_SEAL_WRITE, for reports
+pub fn seal_names(file: &File) -> Vec<&'static str> {
+    seals(file).iter_names().map(|(name, _)| name).collect()
+}
+
+/// a sealed memfd holding everything read from r
+pub fn from_reader<R: Read>(name: &str, mut r: R) -> anyhow::Result<File> {
+    let mut file = create(name)?;
+    io::copy(&mut r, &mut file)?;
+    seal(&file)?;
+    // the offset is shared with the receiver, leave it at the start
+    file.rewind()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{SeekFrom, Write};
+    use std::os::unix::fs::FileExt;
+
+    #[test]
+    fn sealed_content_is_immutable() -> anyhow::Result<()> {
+        let mut file = from_reader("memfd-test", &b"generated"[..])?;
+        assert_eq!(file.metadata()?.len(), 9);
+        assert_eq!(file.stream_position()?, 0);
+        let mut buf = [0; 9];
+        file.read_exact_at(&mut buf, 0)?;
+        assert_eq!(&buf, b"generated");
+
+        assert!(file.write_all_at(b"G", 0).is_err());
+        assert!(file.set_len(3).is_err());
+        file.seek(SeekFrom::End(0))?;
+        assert!(file.write_all(b"more").is_err());
+        assert!(is_immutable(&file));
+        assert!(seal_names(&file).contains(&"F_SEAL_SEAL"));
+        assert!(!is_immutable(&create("memfd-test")?));
+        assert!(!is_immutable(&File::open(env!("CARGO_MANIFEST_DIR"))?));
+        // and the seals can't be lifted
+        assert!(fcntl(file.as_raw_fd(), FcntlArg::F_ADD_SEALS(SealFlag::F_SEAL_SEAL)).is_err());
+        Ok(())
+    }
+}
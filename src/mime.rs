@@ -0,0 +1,332 @@
+// mime type detection
+//
+// the default chain trusts magic bytes first, then the file name, and only
+// then guesses text from the content, so foo.rs stays text/x-rust but an
+// extensionless script or binary still gets a sensible type
+
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// how much of a file the detectors get to see
+pub const HEAD_LEN: usize = 1024;
+
+pub trait MimeDetector: Send + Sync {
+    /// head is the start of the file, empty when there is no content to look at
+    fn detect(&self, path: &Path, head: &[u8]) -> Option<String>;
+}
+
+/// tries each detector in turn, octet-stream when none of them know
+#[derive(Clone)]
+pub struct Detectors(pub Vec<Arc<dyn MimeDetector>>);
+
+impl Default for Detectors {
+    fn default() -> Self {
+        Self(vec![Arc::new(MagicDetector), Arc::new(ExtensionDetector), Arc::new(TextDetector)])
+    }
+}
+
+impl std::fmt::Debug for Detectors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Detectors({})", self.0.len())
+    }
+}
+
+impl Detectors {
+    /// consult a mime.types table of the user's right after the magic bytes, ahead of the built in names
+    pub fn with_mime_types(mut self, table: MimeTypes) -> Self {
+        let at = self.0.len().min(1);
+        self.0.insert(at, Arc::new(table));
+        self
+    }
+
+    /// consult the system table only for names nothing else knows, before guessing from the content
+    // /etc/mime.types has its own ideas, eg rs is application/rls-services+xml on debian
+    pub fn with_system_types(mut self, table: MimeTypes) -> Self {
+        let at = self.0.len().saturating_sub(1);
+        self.0.insert(at, Arc::new(table));
+        self
+    }
+
+    /// the name alone, for when the content can't or shouldn't be read
+    pub fn detect_by_name(&self, path: &Path) -> String {
+        self.detect(path, &[])
+    }
+
+    pub fn detect(&self, path: &Path, head: &[u8]) -> String {
+        self.0
+            .iter()
+            .find_map(|d| d.detect(path, head))
+            .unwrap_or_else(|| OCTET_STREAM.to_string())
+    }
+
+    // reads the head without moving the file offset, which may be shared with the receiver
+    pub fn sniff(&self, path: &Path, file: &File) -> String {
+        let mut head = vec![0; HEAD_LEN];
+        let n = file.read_at(&mut head, 0).unwrap_or(0);
+        self.detect(path, &head[..n])
+    }
+}
+
+/// the name alone, from the built in table only
+pub fn detect_mime_type(path: &Path) -> String {
+    ExtensionDetector.detect(path, &[]).unwrap_or_else(|| OCTET_STREAM.to_string())
+}
+
+/// extension mappings in the mime.types format
+///
+/// ```text
+/// # comment
+/// type/subtype  ext1 ext2
+/// ```
+///
+/// later files override earlier ones, and the longest matching suffix wins so
+/// an entry for tar.gz beats one for gz
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    by_ext: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    pub const SYSTEM: &'static str = "/etc/mime.types";
+    /// looked for in the dir being sent or served
+    pub const PROJECT: &'static str = ".mime.types";
+
+    /// /etc/mime.types, empty if there isn't one
+    pub fn system() -> anyhow::Result<Self> {
+        let mut table = Self::default();
+        if Path::new(Self::SYSTEM).exists() {
+            table.load(Path::new(Self::SYSTEM))?;
+        }
+        Ok(table)
+    }
+
+    // a user supplied file, then project local overrides
+    pub fn discover(project_dir: Option<&Path>, user: Option<&Path>) -> anyhow::Result<Self> {
+        let mut table = Self::default();
+        // only a user supplied file has to exist
+        if let Some(user) = user {
+            table.load(user)?;
+        }
+        if let Some(project) = project_dir.map(|dir| dir.join(Self::PROJECT))
+            && project.exists()
+        {
+            table.load(&project)?;
+        }
+        Ok(table)
+    }
+
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        self.extend(&Self::parse(&text));
+        Ok(())
+    }
+
+    // the first mapping of an extension within one file wins
+    pub fn parse(text: &str) -> Self {
+        let mut by_ext = HashMap::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(mime) = fields.next() else {
+                continue;
+            };
+            for ext in fields {
+                let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+                by_ext.entry(ext).or_insert_with(|| mime.to_string());
+            }
+        }
+        Self { by_ext }
+    }
+
+    pub fn extend(&mut self, other: &MimeTypes) {
+        self.by_ext.extend(other.by_ext.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&str> {
+        let name = name.to_ascii_lowercase();
+        // a leading dot is a hidden file, not an extension
+        let name = name.strip_prefix('.').unwrap_or(&name);
+        name.match_indices('.')
+            .find_map(|(i, _)| self.by_ext.get(&name[i + 1..]))
+            .map(String::as_str)
+    }
+}
+
+impl MimeDetector for MimeTypes {
+    fn detect(&self, path: &Path, _head: &[u8]) -> Option<String> {
+        let name = path.file_name()?.to_str()?;
+        self.lookup(name).map(str::to_string)
+    }
+}
+
+/// well known file names and extensions
+pub struct ExtensionDetector;
+
+impl MimeDetector for ExtensionDetector {
+    fn detect(&self, path: &Path, _head: &[u8]) -> Option<String> {
+        let by_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some("Makefile") | Some("makefile") | Some("GNUmakefile") => Some("text/x-makefile"),
+            Some("Dockerfile") => Some("text/x-dockerfile"),
+            Some("Cargo.lock") => Some("application/toml"),
+            _ => None,
+        };
+        let mime = by_name.or(match path.extension().and_then(|ext| ext.to_str()) {
+            Some("txt") => Some("text/plain"),
+            Some("html") | Some("htm") => Some("text/html"),
+            Some("css") => Some("text/css"),
+            Some("js") => Some("application/javascript"),
+            Some("json") => Some("application/json"),
+            Some("png") => Some("image/png"),
+            Some("jpg") | Some("jpeg") => Some("image/jpeg"),
+            Some("gif") => Some("image/gif"),
+            Some("pdf") => Some("application/pdf"),
+            Some("zip") => Some("application/zip"),
+            Some("rs") => Some("text/x-rust"),
+            Some("py") => Some("text/x-python"),
+            Some("c") => Some("text/x-c"),
+            Some("cpp") | Some("cc") => Some("text/x-c++"),
+            Some("toml") => Some("application/toml"),
+            Some("md") => Some("text/markdown"),
+            _ => None,
+        });
+        mime.map(str::to_string)
+    }
+}
+
+/// signatures that identify a format regardless of name
+pub struct MagicDetector;
+
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x7fELF", "application/x-elf"),
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"BZh", "application/x-bzip2"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"\x00asm", "application/wasm"),
+];
+
+impl MimeDetector for MagicDetector {
+    fn detect(&self, _path: &Path, head: &[u8]) -> Option<String> {
+        if let Some((_, mime)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+            return Some(mime.to_string());
+        }
+        if head.get(257..262) == Some(b"ustar") {
+            return Some("application/x-tar".to_string());
+        }
+        if let Some(line) = head.strip_prefix(b"#!") {
+            return Some(shebang(line).to_string());
+        }
+        None
+    }
+}
+
+fn shebang(line: &[u8]) -> &'static str {
+    let line = line.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut words = line.split_whitespace();
+    let mut interpreter = words.next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    // #!/usr/bin/env python3
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-')).unwrap_or_default();
+    }
+    match interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "sh" | "bash" | "dash" | "zsh" | "ksh" => "text/x-shellscript",
+        "python" => "text/x-python",
+        "perl" => "text/x-perl",
+        "ruby" => "text/x-ruby",
+        "node" => "application/javascript",
+        _ => "text/x-script",
+    }
+}
+
+/// plain text by content, for anything the name didn't give away
+pub struct TextDetector;
+
+impl MimeDetector for TextDetector {
+    fn detect(&self, _path: &Path, head: &[u8]) -> Option<String> {
+        if head.starts_with(b"\xff\xfe") {
+            return Some("text/plain; charset=utf-16le".to_string());
+        }
+        if head.starts_with(b"\xfe\xff") {
+            return Some("text/plain; charset=utf-16be".to_string());
+        }
+        if head.is_empty() || head.contains(&0) {
+            return None;
+        }
+        match std::str::from_utf8(head) {
+            Ok(_) => Some("text/plain".to_string()),
+            // the head may end part way through a character
+            Err(e) if e.error_len().is_none() => Some("text/plain".to_string()),
+            Err(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffing_beats_names() {
+        let d = Detectors::default();
+        assert_eq!(d.detect(Path::new("a.txt"), b"\x7fELF\x02\x01"), "application/x-elf");
+        assert_eq!(d.detect(Path::new("run"), b"#!/usr/bin/env python3\nprint()"), "text/x-python");
+        assert_eq!(d.detect(Path::new("run"), b"#!/bin/sh\n"), "text/x-shellscript");
+        assert_eq!(d.detect(Path::new("lib.rs"), b"use std;"), "text/x-rust");
+        assert_eq!(d.detect(Path::new("Makefile"), b"all:\n"), "text/x-makefile");
+        assert_eq!(d.detect(Path::new("README"), "caf\u{e9}".as_bytes()), "text/plain");
+        assert_eq!(d.detect(Path::new("README"), &"caf\u{e9}".as_bytes()[..4]), "text/plain");
+        assert_eq!(d.detect(Path::new("notes"), b"\xff\xfeh\x00i\x00"), "text/plain; charset=utf-16le");
+        assert_eq!(d.detect(Path::new("blob"), b"\x00\x01\x02"), OCTET_STREAM);
+        assert_eq!(d.detect(Path::new("blob"), b""), OCTET_STREAM);
+    }
+
+    #[test]
+    fn mime_types_tables() {
+        let mut table = MimeTypes::parse("# system\napplication/gzip gz\ntext/x-rust rs\ntext/x-other rs\n");
+        assert_eq!(table.lookup("a.rs"), Some("text/x-rust"));
+        assert_eq!(table.lookup("A.GZ"), Some("application/gzip"));
+        assert_eq!(table.lookup(".gz"), None);
+        assert_eq!(table.lookup("gz"), None);
+
+        table.extend(&MimeTypes::parse("application/x-compressed-tar tar.gz tgz\ntext/x-mine rs"));
+        assert_eq!(table.lookup("src.tar.gz"), Some("application/x-compressed-tar"));
+        assert_eq!(table.lookup("other.gz"), Some("application/gzip"));
+        assert_eq!(table.lookup("a.rs"), Some("text/x-mine"));
+
+        let d = Detectors::default().with_mime_types(table);
+        assert_eq!(d.detect_by_name(Path::new("x/lib.rs")), "text/x-mine");
+        // content still wins over any name
+        assert_eq!(d.detect(Path::new("src.tar.gz"), b"\x7fELF"), "application/x-elf");
+    }
+
+    #[test]
+    fn the_system_table_only_fills_gaps() {
+        let system = MimeTypes::parse("application/rls-services+xml rs\napplication/x-foo foo\n");
+        let user = MimeTypes::parse("text/x-bar bar\n");
+        let d = Detectors::default().with_system_types(system.clone()).with_mime_types(user.clone());
+        assert_eq!(d.detect_by_name(Path::new("lib.rs")), "text/x-rust");
+        assert_eq!(d.detect_by_name(Path::new("a.foo")), "application/x-foo");
+        assert_eq!(d.detect_by_name(Path::new("a.bar")), "text/x-bar");
+        // either way round
+        let d = Detectors::default().with_mime_types(user).with_system_types(system.clone());
+        assert_eq!(d.detect_by_name(Path::new("lib.rs")), "text/x-rust");
+        assert_eq!(d.detect(Path::new("a.foo"), b"plain text"), "application/x-foo");
+        // but the user's own files beat the built in names
+        let d = Detectors::default().with_system_types(system.clone()).with_mime_types(system);
+        assert_eq!(d.detect_by_name(Path::new("lib.rs")), "application/rls-services+xml");
+    }
+}
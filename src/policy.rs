@@ -0,0 +1,235 @@
+// broker access policy
+//
+// one rule per line, blank lines and anything after a `#` are ignored
+//
+//     <prefix> <ro|rw> [types]
+//
+// types is a comma separated list of file, dir, symlink, block, char, fifo, socket
+// and defaults to file. the longest prefix covering a requested path decides.
+
+use crate::frame::OpenRequest;
+use crate::mime::Detectors;
+use crate::serve::Opener;
+use crate::{FileMetadata, FileType};
+use anyhow::{bail, Context};
+use nix::fcntl::{open, openat2, OFlag, OpenHow, ResolveFlag};
+use nix::sys::stat::Mode;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub prefix: PathBuf,
+    pub access: Access,
+    pub types: Vec<FileType>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    detectors: Detectors,
+}
+
+impl Policy {
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read policy {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            rules.push(parse_rule(line).with_context(|| format!("policy line {}", n + 1))?);
+        }
+        Ok(Self {
+            rules,
+            detectors: Detectors::default(),
+        })
+    }
+
+    pub fn with_detectors(mut self, detectors: Detectors) -> Self {
+        self.detectors = detectors;
+        self
+    }
+
+    // the most specific rule covering path
+    pub fn rule_for(&self, path: &Path) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .filter(|r| path.starts_with(&r.prefix))
+            .max_by_key(|r| r.prefix.components().count())
+    }
+}
+
+fn parse_rule(line: &str) -> anyhow::Result<Rule> {
+    let mut fields = line.split_whitespace();
+    let prefix = PathBuf::from(fields.next().unwrap_or_default());
+    if !prefix.is_absolute() || prefix.components().any(|c| c == Component::ParentDir) {
+        bail!("prefix {} must be absolute and free of ..", prefix.display());
+    }
+    let access = match fields.next() {
+        Some("ro") => Access::ReadOnly,
+        Some("rw") => Access::ReadWrite,
+        Some(other) => bail!("unknown access {other}, expected ro or rw"),
+        None => bail!("missing access for {}", prefix.display()),
+    };
+    let types = match fields.next() {
+        Some(list) => list.split(',').map(str::parse).collect::<anyhow::Result<_>>()?,
+        None => vec![FileType::RegularFile],
+    };
+    if let Some(extra) = fields.next() {
+        bail!("unexpected {extra}");
+    }
+    Ok(Rule { prefix, access, types })
+}
+
+impl Opener for Policy {
+    fn open(&self, req: &OpenRequest) -> anyhow::Result<(FileMetadata, File)> {
+        let path = Path::new(&req.path);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            bail!("{} must be absolute and free of ..", req.path);
+        }
+        let Some(rule) = self.rule_for(path) else {
+            bail!("{} is not covered by the policy", req.path);
+        };
+        if req.write && rule.access == Access::ReadOnly {
+            bail!("{} is read-only", req.path);
+        }
+
+        // the type is checked before a real open
+        let rel = path.strip_prefix(&rule.prefix)?;
+        let (root, handle) = open_beneath(&rule.prefix, rel)?;
+
+        // described from the handle, looking anything up by name again would leave the prefix
+        let mut metadata = FileMetadata::from_fd(path, &handle)?;
+        if !rule.types.contains(&metadata.file_type) {
+            bail!("{} is a {:?}, not allowed here", req.path, metadata.file_type);
+        }
+
+        // a symlink can't be reopened, the O_PATH handle is all there is
+        if let Some(target) = &metadata.symlink_target {
+            metadata.dangling = !reachable(&root, rel, Path::new(target), &rule.prefix);
+            return Ok((metadata, handle));
+        }
+        // opening a fifo or device blocks or has side effects, they are handed over as the O_PATH handle
+        if !matches!(metadata.file_type, FileType::RegularFile | FileType::Directory) {
+            if req.write {
+                bail!("{} is a {:?}, only files can be opened for writing", req.path, metadata.file_type);
+            }
+            return Ok((metadata, handle));
+        }
+
+        // reopen the resolved handle with the requested access
+        let flags = if req.write { OFlag::O_RDWR } else { OFlag::O_RDONLY };
+        let proc_path = format!("/proc/self/fd/{}", handle.as_raw_fd());
+        let file = File::from(fd(open(proc_path.as_str(), flags | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, Mode::empty())?));
+        metadata.sniff_mime(&file, &self.detectors);
+        Ok((metadata, file))
+    }
+}
+
+/// an O_PATH handle for rel, resolved beneath root without following any symlinks so nothing can escape it,
+/// and the root it was resolved from. O_PATH has no side effects on devices or fifos
+pub fn open_beneath(root: &Path, rel: &Path) -> anyhow::Result<(OwnedFd, File)> {
+    let rel = if rel.as_os_str().is_empty() { Path::new(".") } else { rel };
+    let root = fd(open(root, OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?);
+    let how = OpenHow::new()
+        .flags(OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC)
+        .resolve(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_NO_SYMLINKS);
+    let handle = File::from(fd(openat2(root.as_raw_fd(), rel, how)?));
+    Ok((root, handle))
+}
+
+/// whether a link's target exists, resolved beneath the prefix like the link was
+/// a target outside the prefix can't be reached through the broker, it counts as dangling
+pub fn reachable(root: &OwnedFd, link: &Path, target: &Path, prefix: &Path) -> bool {
+    let target = if target.is_absolute() {
+        match target.strip_prefix(prefix) {
+            Ok(rel) => rel.to_path_buf(),
+            Err(_) => return false,
+        }
+    } else {
+        link.parent().unwrap_or(Path::new("")).join(target)
+    };
+    let target = if target.as_os_str().is_empty() { Path::new(".") } else { &target };
+    let how = OpenHow::new().flags(OFlag::O_PATH | OFlag::O_CLOEXEC).resolve(ResolveFlag::RESOLVE_BENEATH);
+    openat2(root.as_raw_fd(), target, how).map(fd).is_ok()
+}
+
+fn fd(raw: std::os::fd::RawFd) -> OwnedFd {
+    unsafe { OwnedFd::from_raw_fd(raw) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn longest_prefix_wins() -> anyhow::Result<()> {
+        let policy = Policy::parse(
+            "# test policy\n\
+             /srv ro\n\
+             /srv/data rw file,dir  # writable\n",
+        )?;
+        assert_eq!(policy.rule_for(Path::new("/srv/data/x")).unwrap().access, Access::ReadWrite);
+        assert_eq!(policy.rule_for(Path::new("/srv/other")).unwrap().access, Access::ReadOnly);
+        assert!(policy.rule_for(Path::new("/srvx")).is_none());
+        assert!(Policy::parse("relative ro").is_err());
+        assert!(Policy::parse("/srv xx").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn symlinks_cannot_escape() -> anyhow::Result<()> {
+        let root = TestDir::new("policy");
+        fs::create_dir_all(root.join("allowed"))?;
+        fs::write(root.join("allowed/ok.txt"), "ok")?;
+        fs::write(root.join("secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(root.join("secret.txt"), root.join("allowed/link.txt"))?;
+
+        let policy = Policy::parse(&format!("{}/allowed ro", root.display()))?;
+        let req = |p: &str, write| OpenRequest {
+            path: root.join(p).to_string_lossy().to_string(),
+            write,
+        };
+
+        assert!(policy.open(&req("allowed/ok.txt", false)).is_ok());
+        assert!(policy.open(&req("allowed/ok.txt", true)).is_err());
+        assert!(policy.open(&req("allowed/link.txt", false)).is_err());
+        assert!(policy.open(&req("secret.txt", false)).is_err());
+        assert!(policy.open(&req("allowed", false)).is_err());
+
+        // links are described without following them past the prefix
+        std::os::unix::fs::symlink("ok.txt", root.join("allowed/near"))?;
+        std::os::unix::fs::symlink("missing", root.join("allowed/gone"))?;
+        let links = Policy::parse(&format!("{}/allowed ro file,symlink", root.display()))?;
+        let (meta, _) = links.open(&req("allowed/link.txt", false))?;
+        assert_eq!(meta.symlink_target, Some(root.join("secret.txt").to_string_lossy().to_string()));
+        assert!(meta.dangling);
+        assert!(!links.open(&req("allowed/near", false))?.0.dangling);
+        assert!(links.open(&req("allowed/gone", false))?.0.dangling);
+
+        // a fifo comes back as O_PATH instead of waiting for a writer
+        nix::unistd::mkfifo(&root.join("allowed/fifo"), Mode::from_bits_truncate(0o600))?;
+        let fifos = Policy::parse(&format!("{}/allowed rw fifo", root.display()))?;
+        let (meta, file) = fifos.open(&req("allowed/fifo", false))?;
+        assert_eq!(meta.file_type, FileType::Fifo);
+        assert!(nix::fcntl::fcntl(file.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFL)? & OFlag::O_PATH.bits() != 0);
+        assert!(fifos.open(&req("allowed/fifo", true)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+// a short, safe look at the start of a file
+//
+// text is cut at a character boundary, never in the middle of one, and
+// anything that isn't text in a known encoding is shown as a hexdump.
+// control characters other than tab and newline are shown escaped, they
+// would otherwise reach the terminal
+
+use serde::Serialize;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct PreviewOpts {
+    /// characters of text to preview, or bytes of a hexdump
+    #[clap(long = "preview-len", global = true, default_value_t = 128)]
+    pub len: usize,
+    /// lines to preview at most
+    #[clap(long = "preview-lines", global = true, default_value_t = 8)]
+    pub lines: usize,
+}
+
+impl Default for PreviewOpts {
+    fn default() -> Self {
+        Self { len: 128, lines: 8 }
+    }
+}
+
+impl PreviewOpts {
+    /// how much of the file is needed, a character can take up to 4 bytes
+    pub fn head_len(&self) -> usize {
+        self.len.saturating_mul(4).max(16)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Encoding {
+    #[serde(rename = "utf-8")]
+    Utf8,
+    #[serde(rename = "utf-16le")]
+    Utf16Le,
+    #[serde(rename = "utf-16be")]
+    Utf16Be,
+    #[serde(rename = "latin-1")]
+    Latin1,
+    Binary,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin-1",
+            Encoding::Binary => "binary",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Preview {
+    pub encoding: Encoding,
+    /// the text, or a hexdump for binary
+    pub text: String,
+    /// whether there was more than what's shown
+    pub truncated: bool,
+}
+
+/// head is the start of the file, complete is whether it is the whole file
+pub fn preview(head: &[u8], complete: bool, opts: &PreviewOpts) -> Preview {
+    let encoding = detect(head, complete);
+    let (text, truncated) = match encoding {
+        Encoding::Utf8 => truncate(&utf8(head), opts),
+        Encoding::Utf16Le => truncate(&utf16(head, u16::from_le_bytes), opts),
+        Encoding::Utf16Be => truncate(&utf16(head, u16::from_be_bytes), opts),
+        // every byte is its own code point
+        Encoding::Latin1 => truncate(&head.iter().map(|b| *b as char).collect::<String>(), opts),
+        Encoding::Binary => hexdump(head, opts),
+    };
+    Preview {
+        encoding,
+        text: escape(&text),
+        truncated: truncated || !complete,
+    }
+}
+
+pub fn detect(head: &[u8], complete: bool) -> Encoding {
+    if head.starts_with(b"\xff\xfe") {
+        return Encoding::Utf16Le;
+    }
+    if head.starts_with(b"\xfe\xff") {
+        return Encoding::Utf16Be;
+    }
+    if !head.contains(&0) {
+        match std::str::from_utf8(head) {
+            Ok(_) => return Encoding::Utf8,
+            // a head that isn't the whole file may end part way through a character
+            Err(e) if e.error_len().is_none() && !complete => return Encoding::Utf8,
+            Err(_) => {}
+        }
+        // C1 controls almost never show up in real latin-1 text
+        if head.iter().all(|b| text_byte(*b) || *b >= 0xa0) {
+            return Encoding::Latin1;
+        }
+        return Encoding::Binary;
+    }
+    // ascii in utf-16 without a bom, every other byte is zero
+    let (even, odd) = zeros(head);
+    let pairs = head.len() / 2;
+    if pairs > 0 && odd == pairs && even == 0 && head.iter().step_by(2).all(|b| text_byte(*b)) {
+        return Encoding::Utf16Le;
+    }
+    if pairs > 0 && even == pairs && odd == 0 && head.iter().skip(1).step_by(2).all(|b| text_byte(*b)) {
+        return Encoding::Utf16Be;
+    }
+    Encoding::Binary
+}
+
+fn text_byte(b: u8) -> bool {
+    matches!(b, b'\t' | b'\n' | b'\r' | 0x20..=0x7e)
+}
+
+// zero bytes at even and odd offsets
+fn zeros(head: &[u8]) -> (usize, usize) {
+    let pairs = head.chunks_exact(2);
+    pairs.fold((0, 0), |(even, odd), p| (even + (p[0] == 0) as usize, odd + (p[1] == 0) as usize))
+}
+
+// a truncated final character is dropped
+fn utf8(head: &[u8]) -> String {
+    match std::str::from_utf8(head) {
+        Ok(s) => s.to_string(),
+        Err(e) => String::from_utf8_lossy(&head[..e.valid_up_to()]).to_string(),
+    }
+}
+
+fn utf16(head: &[u8], decode: fn([u8; 2]) -> u16) -> String {
+    let bom = matches!(head, [0xff, 0xfe, ..] | [0xfe, 0xff, ..]);
+    let head = if bom { &head[2..] } else { head };
+    let units = head.chunks_exact(2).map(|p| decode([p[0], p[1]]));
+    let mut text: String = char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect();
+    // a surrogate pair cut in half at the end of the head
+    if text.ends_with(char::REPLACEMENT_CHARACTER) {
+        text.pop();
+    }
+    text
+}
+
+// at most len characters and lines lines
+fn truncate(text: &str, opts: &PreviewOpts) -> (String, bool) {
+    let mut out = String::new();
+    let mut lines = 0;
+    for (chars, c) in text.chars().enumerate() {
+        if chars == opts.len {
+            return (out, true);
+        }
+        if c == '\n' {
+            lines += 1;
+            if lines == opts.lines {
+                return (out, chars + 1 < text.chars().count());
+            }
+        }
+        out.push(c);
+    }
+    (out, false)
+}
+
+// eg \u{1b} for ESC, and \r, so a preview can't move the cursor or recolor the terminal
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' | '\n' => out.push(c),
+            // bidi overrides reorder what follows them
+            c if c.is_control() || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}') => {
+                out.extend(c.escape_default())
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// xxd style, 16 bytes a line
+fn hexdump(head: &[u8], opts: &PreviewOpts) -> (String, bool) {
+    let shown = head.len().min(opts.len).min(opts.lines.saturating_mul(16));
+    let mut out = String::new();
+    for (i, row) in head[..shown].chunks(16).enumerate() {
+        let _ = write!(out, "{:08x}:", i * 16);
+        for (j, b) in row.iter().enumerate() {
+            if j % 2 == 0 {
+                out.push(' ');
+            }
+            let _ = write!(out, "{b:02x}");
+        }
+        // line the ascii column up on a short last row
+        let missing = 16 - row.len();
+        out.push_str(&" ".repeat(missing * 2 + missing / 2 + 2));
+        out.extend(row.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }));
+        out.push('\n');
+    }
+    out.pop();
+    (out, shown < head.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTS: PreviewOpts = PreviewOpts { len: 5, lines: 8 };
+
+    #[test]
+    fn cuts_at_character_boundaries() {
+        let text = "h\u{e9}ll\u{f6} w\u{f6}rld";
+        let p = preview(text.as_bytes(), true, &OPTS);
+        assert_eq!((p.encoding, p.text.as_str(), p.truncated), (Encoding::Utf8, "h\u{e9}ll\u{f6}", true));
+        // the head ends inside the \u{e9}
+        let p = preview(&text.as_bytes()[..2], false, &OPTS);
+        assert_eq!(p.text, "h");
+        let p = preview(b"a\nb\nc\n", true, &PreviewOpts { len: 100, lines: 2 });
+        assert_eq!((p.text.as_str(), p.truncated), ("a\nb", true));
+        assert!(!preview(b"abc", true, &OPTS).truncated);
+    }
+
+    #[test]
+    fn escapes_controls() {
+        let p = preview(b"\x1b[2Ja\tb\r\n\x07", true, &PreviewOpts { len: 100, lines: 8 });
+        assert_eq!(p.text, "\\u{1b}[2Ja\tb\\r\n\\u{7}");
+        assert_eq!(preview("x\u{202e}y\u{85}".as_bytes(), true, &OPTS).text, "x\\u{202e}y\\u{85}");
+    }
+
+    #[test]
+    fn detects_encodings() {
+        assert_eq!(detect(b"\xff\xfeh\x00i\x00", true), Encoding::Utf16Le);
+        assert_eq!(detect(b"h\x00i\x00\n\x00", true), Encoding::Utf16Le);
+        assert_eq!(detect(b"\x00h\x00i", true), Encoding::Utf16Be);
+        assert_eq!(preview(b"\xfe\xff\x00h\x00i", true, &OPTS).text, "hi");
+        assert_eq!(detect(b"caf\xe9", true), Encoding::Latin1);
+        assert_eq!(detect(b"caf\xe9", false), Encoding::Utf8);
+        assert_eq!(preview(b"caf\xe9", true, &OPTS).text, "caf\u{e9}");
+        assert_eq!(detect(b"\x7fELF\x02\x01\x01\x00", true), Encoding::Binary);
+        assert_eq!(detect(b"\x80\x81", true), Encoding::Binary);
+    }
+
+    #[test]
+    fn hexdumps_binary() {
+        let p = preview(b"\x00\x01ABCDEFGHIJKLMNOPQRSTUVWXYZ", true, &PreviewOpts { len: 18, lines: 8 });
+        assert_eq!(p.encoding, Encoding::Binary);
+        assert_eq!(
+            p.text,
+            "00000000: 0001 4142 4344 4546 4748 494a 4b4c 4d4e  ..ABCDEFGHIJKLMN\n\
+             00000010: 4f50                                     OP"
+        );
+        assert!(p.truncated);
+    }
+}
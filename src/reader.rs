@@ -0,0 +1,200 @@
+// reading received fds without touching their file offset
+//
+// a received fd shares its open file description, and so its offset, with
+// the sender. read() and seek() would move it for everyone, pread doesn't,
+// and a reopen through /proc/self/fd gets a description of its own.
+
+use futures_util::Stream;
+use crate::memfd;
+use memmap2::Mmap;
+use nix::fcntl::SealFlag;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+use tokio::task;
+
+pub const CHUNK: usize = 64 * 1024;
+
+/// sealed files at least this big are better mapped than read in chunks
+pub const MAP_THRESHOLD: u64 = 16 << 20;
+
+// each_chunk's one buffer, whole files are read in bigger pieces than streamed ones
+const READ_BUF: usize = 1 << 20;
+
+/// Read and Seek on a private position, every read is a pread
+#[derive(Debug)]
+pub struct PositionalReader {
+    file: File,
+    pos: u64,
+}
+
+impl PositionalReader {
+    /// starts at offset 0 whatever the file offset is
+    pub fn new(file: File) -> Self {
+        Self { file, pos: 0 }
+    }
+}
+
+impl Read for PositionalReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for PositionalReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let base = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::Current(n) => (self.pos, n),
+            SeekFrom::End(n) => (self.file.metadata()?.len(), n),
+        };
+        self.pos = base
+            .0
+            .checked_add_signed(base.1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.pos)
+    }
+}
+
+/// the whole file from offset 0 as chunks, each pread on the blocking pool
+pub fn chunks(file: File) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    let file = Arc::new(file);
+    futures_util::stream::try_unfold(0u64, move |offset| {
+        let file = file.clone();
+        async move {
+            let chunk = task::spawn_blocking(move || read_chunk(&file, offset)).await??;
+            Ok(if chunk.is_empty() {
+                None
+            } else {
+                let next = offset + chunk.len() as u64;
+                Some((chunk, next))
+            })
+        }
+    })
+}
+
+/// up to len bytes from offset 0, on the blocking pool
+pub async fn head(file: File, len: usize) -> io::Result<Vec<u8>> {
+    task::spawn_blocking(move || {
+        let mut head = Vec::with_capacity(len);
+        PositionalReader::new(file).take(len as u64).read_to_end(&mut head)?;
+        Ok(head)
+    })
+    .await?
+}
+
+/// the whole file from offset 0 through one reused buffer, blocking
+pub fn each_chunk(file: &File, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = vec![0; READ_BUF];
+    let mut offset = 0;
+    loop {
+        match file.read_at(&mut buf, offset) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                f(&buf[..n]);
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_chunk(file: &File, offset: u64) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; CHUNK];
+    loop {
+        match file.read_at(&mut buf, offset) {
+            Ok(n) => {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// whether map will take the file, only a memfd sealed against shrinking
+pub fn mappable(file: &File) -> bool {
+    memfd::seals(file).contains(SealFlag::F_SEAL_SHRINK)
+}
+
+/// the file mapped read only, pages are only read in as they are touched
+// the mapping is the file itself, not a copy. a sender that truncated it
+// while it was mapped would get the whole receiver killed with a SIGBUS,
+// so anything that can still shrink is refused
+pub fn map(file: &File) -> io::Result<Mmap> {
+    if !mappable(file) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "only files sealed against shrinking are mapped"));
+    }
+    unsafe { Mmap::map(file) }
+}
+
+/// any file mapped read only, for rx --mmap
+///
+/// # Safety
+/// if the file shrinks while it is mapped, touching the missing pages kills the process with a SIGBUS
+pub unsafe fn map_unsealed(file: &File) -> io::Result<Mmap> {
+    unsafe { Mmap::map(file) }
+}
+
+/// a new open file description for the same file, with its own offset
+/// fails where the path can't be opened, eg for a file this process has no permission to open
+pub fn reopen(file: &File) -> io::Result<File> {
+    let proc_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    let fd = open(proc_path.as_str(), OFlag::O_RDONLY | OFlag::O_NOCTTY | OFlag::O_CLOEXEC, Mode::empty())?;
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use futures_util::TryStreamExt;
+
+    #[tokio::test]
+    async fn reads_never_move_the_shared_offset() -> io::Result<()> {
+        let dir = TestDir::new("reader");
+        let path = dir.join("f");
+        let data: Vec<u8> = (0..CHUNK * 2 + 10).map(|i| i as u8).collect();
+        std::fs::write(&path, &data)?;
+        let mut file = File::open(&path)?;
+        file.seek(SeekFrom::Start(7))?;
+
+        let mut all = vec![];
+        PositionalReader::new(file.try_clone()?).read_to_end(&mut all)?;
+        assert_eq!(all, data);
+        let streamed: Vec<Vec<u8>> = chunks(file.try_clone()?).try_collect().await?;
+        assert_eq!(streamed.concat(), data);
+        let mut each = vec![];
+        each_chunk(&file, |chunk| each.extend_from_slice(chunk))?;
+        assert_eq!(each, data);
+        assert_eq!(head(file.try_clone()?, 3).await?, [0, 1, 2]);
+        let mut reopened = reopen(&file)?;
+        let mut first = [0; 2];
+        reopened.read_exact(&mut first)?;
+        assert_eq!(first, [0, 1]);
+
+        let mut r = PositionalReader::new(file.try_clone()?);
+        assert_eq!(r.seek(SeekFrom::End(-1))?, data.len() as u64 - 1);
+        assert!(r.seek(SeekFrom::Current(-(data.len() as i64))).is_err());
+
+        assert_eq!(file.stream_position()?, 7);
+
+        // the sender could truncate a plain file under the mapping
+        assert!(map(&file).is_err());
+        let sealed = memfd::from_reader("reader-test", &data[..]).map_err(io::Error::other)?;
+        assert_eq!(&map(&sealed)?[..], data);
+        assert_eq!(&unsafe { map_unsealed(&file)? }[..], data);
+        Ok(())
+    }
+}
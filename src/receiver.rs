@@ -1,89 +1,216 @@
 extern crate core;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use example_tokio_uds_fd::consumer::{Chain, Pool};
+use example_tokio_uds_fd::frame::{self, OpenRequest};
+use example_tokio_uds_fd::hash::Algorithm;
+use example_tokio_uds_fd::preview::PreviewOpts;
+use example_tokio_uds_fd::report::{self, Format};
 use example_tokio_uds_fd::{consumer, FileMetadata, Msg};
-use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
 use std::fs;
 use std::fs::File;
-use std::io::{IoSliceMut, Read};
-use std::os::fd::{FromRawFd, OwnedFd};
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::exit;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use anyhow::bail;
-use nix::cmsg_space;
 use nix::errno::Errno;
+use serde_json::json;
 use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::mpsc::{channel, Sender};
+use tokio::task;
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Opts {
     /// path to create socket at
-    socket_path: PathBuf,
+    #[arg(required = true)]
+    socket_path: Option<PathBuf>,
+    /// digests to compute for each file, in one pass: sha256, sha1, sha512, blake3, xxh3, crc32c
+    #[clap(long, global = true, value_enum, value_delimiter = ',', default_value = "sha256")]
+    hash: Vec<Algorithm>,
+    /// hash files of 16M or more from a mapping even when they aren't sealed,
+    /// rx is killed with a SIGBUS if one is truncated meanwhile
+    #[clap(long, global = true)]
+    mmap: bool,
+    /// have store hash an existing object again before reusing it, instead of trusting its name
+    #[clap(long, global = true)]
+    verify_store: bool,
+    /// consumers each file goes through, in order: print, hash[:algo,..], copy:DIR, tar:FILE[:gz|zst], store[:algo]:DIR
+    #[clap(short, long = "consumer", global = true, default_values = ["print", "hash"])]
+    consumers: Vec<String>,
+    /// consumers that only get content that can't change under them, ie sealed memfds, eg hash,store
+    #[clap(long, global = true, value_delimiter = ',')]
+    require_sealed: Vec<String>,
+    /// how to report received files and connection events
+    #[clap(long, global = true, value_enum, default_value_t)]
+    format: Format,
+    /// how many files to process at once
+    #[clap(short, long, global = true, default_value_t = 1)]
+    jobs: usize,
+    /// report files in the order they arrived, even with --jobs
+    #[clap(long, global = true)]
+    ordered: bool,
+    #[command(flatten)]
+    preview: PreviewOpts,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+    /// connect to a serving tx and request only the given paths
+    Fetch {
+        /// existing socket to connect to (created by tx serve)
+        socket_path: PathBuf,
+        /// paths to request, relative to the served dir
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// request the files read-write
+        #[clap(short, long)]
+        write: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts: Opts = Opts::parse();
-    if opts.socket_path.exists() {
-        fs::remove_file(&opts.socket_path)?;
+    let chain = Arc::new(Chain::from_specs(&opts.consumers, &opts.hash, opts.mmap, opts.verify_store, opts.preview, &opts.require_sealed)?);
+    let pool = Pool {
+        jobs: opts.jobs,
+        ordered: opts.ordered,
+    };
+    match opts.mode {
+        Some(Mode::Fetch { socket_path, paths, write }) => fetch(socket_path, paths, write, chain, opts.format, pool).await,
+        None => listen(opts.socket_path.expect("socket path"), chain, opts.format, pool).await,
+    }
+}
+
+async fn listen(socket_path: PathBuf, chain: Arc<Chain>, format: Format, pool: Pool) -> anyhow::Result<()> {
+    if socket_path.exists() {
+        fs::remove_file(&socket_path)?;
     }
 
     let (tx, rx) = channel(128);
 
     // external consumer of received data
-    tokio::spawn(consumer::consume(rx));
-
-    let mut rx = SocketRx::new(&opts.socket_path, tx);
-    ctrlc::set_handler({
-        let sock = opts.socket_path.clone();
-        let total_bytes = rx.total_received.clone();
-        move || {
-            println!(
-                "\ntotal bytes received {}\ndone...",
-                total_bytes.load(Ordering::Relaxed)
-            );
-            let _ = fs::remove_file(&sock);
-            exit(0);
-        }
-    })
-    .expect("ctrl+c");
+    let consumer = tokio::spawn(consumer::consume(rx, chain, format, pool));
 
-    println!("starting on socket: {}", opts.socket_path.display());
-    rx.listen().await
+    let mut rx = SocketRx::new(&socket_path, tx, format);
+    let total_bytes = rx.total_received.clone();
+
+    let text = format!("starting on socket: {}", socket_path.display());
+    report::event(format, "starting", text, json!({"socket": socket_path}));
+    tokio::select! {
+        res = rx.listen() => res?,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    // closing the channel lets the consumers finish up, eg write the end of an archive
+    drop(rx);
+    consumer.await?;
+    let total = total_bytes.load(Ordering::Relaxed);
+    report::event(format, "done", format!("\ntotal bytes received {total}\ndone..."), json!({"total_bytes": total}));
+    Ok(())
+}
+
+async fn fetch(
+    socket_path: PathBuf,
+    paths: Vec<String>,
+    write: bool,
+    chain: Arc<Chain>,
+    format: Format,
+    pool: Pool,
+) -> anyhow::Result<()> {
+    let (tx, rx) = channel(128);
+    let consumer = tokio::spawn(consumer::consume(rx, chain, format, pool));
+
+    let text = format!("fetching from socket: {}", socket_path.display());
+    report::event(format, "fetching", text, json!({"socket": socket_path}));
+    task::spawn_blocking(move || fetch_paths(socket_path, paths, write, tx, format)).await??;
+
+    // let the consumer finish with everything fetched
+    consumer.await?;
+    report::event(format, "done", "done...", report::no_fields());
+    Ok(())
+}
+
+// request each path in turn and hand the replies to the consumer
+// syscalls must be made in blocking context
+fn fetch_paths(
+    socket_path: PathBuf,
+    paths: Vec<String>,
+    write: bool,
+    consumer: Sender<Msg>,
+    format: Format,
+) -> anyhow::Result<()> {
+    let stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    report::event(format, "connected", "connected...", report::no_fields());
+
+    for (i, path) in paths.into_iter().enumerate() {
+        let req = bincode::serialize(&OpenRequest { path: path.clone(), write })?;
+        frame::send(stream.as_raw_fd(), frame::OPEN, &req, &[], &[])?;
+
+        let Some(reply) = frame::recv(stream.as_raw_fd())? else {
+            bail!("server hung up");
+        };
+        match reply.kind {
+            frame::FILE => {
+                let metadata = bincode::deserialize(&reply.d1)?;
+                match reply.fds.into_iter().next() {
+                    Some(fd) => consumer.blocking_send(Msg::new(i + 1, metadata, File::from(fd)))?,
+                    None => {
+                        let text = format!("no fd received for {path}");
+                        report::error(format, "fetch_error", &text, json!({"path": path, "error": text}));
+                    }
+                }
+            }
+            frame::ERROR => {
+                let error = String::from_utf8_lossy(&reply.d1);
+                let text = format!("error fetching {path}: {error}");
+                report::error(format, "fetch_error", text, json!({"path": path, "error": error}));
+            }
+            kind => bail!("unexpected frame kind {kind}"),
+        }
+    }
+    Ok(())
 }
 
 struct SocketRx {
     socket_path: String,
     total_received: Arc<AtomicUsize>,
     consumer: Sender<Msg>,
+    format: Format,
 }
 
 impl SocketRx {
-    pub fn new<P: AsRef<Path>>(socket_path: P, consumer: Sender<Msg>) -> Self {
+    pub fn new<P: AsRef<Path>>(socket_path: P, consumer: Sender<Msg>, format: Format) -> Self {
         Self {
             socket_path: socket_path.as_ref().to_string_lossy().to_string(),
             total_received: Arc::new(AtomicUsize::new(0)),
             consumer,
+            format,
         }
     }
 
     pub async fn listen(&mut self) -> anyhow::Result<()> {
-        println!("uds @ {}", self.socket_path);
+        let format = self.format;
+        report::event(format, "bind", format!("uds @ {}", self.socket_path), json!({"socket": self.socket_path}));
         let listener = UnixListener::bind(&self.socket_path)?;
 
         let permissions = fs::Permissions::from_mode(0o666);
         fs::set_permissions(&self.socket_path, permissions)?;
 
-        println!("listening...");
+        report::event(format, "listening", "listening...", report::no_fields());
 
         while let Ok((stream, _)) = listener.accept().await {
-            println!("connected...");
+            let peer = stream.peer_cred().ok();
+            let fields = json!({"pid": peer.and_then(|c| c.pid()), "uid": peer.map(|c| c.uid())});
+            report::event(format, "connected", "connected...", fields);
             if let Err(e) = self.handle(stream).await {
-                eprintln!("error handling connection: {e}");
+                let text = format!("error handling connection: {e}");
+                report::error(format, "connection_error", text, json!({"error": e.to_string()}));
             }
         }
         Ok(())
@@ -93,78 +220,51 @@ impl SocketRx {
         let mut i = 0;
         loop {
             stream.readable().await?;
-            let mut cmsg_buf = cmsg_space!(RawFd);
-            let mut header = HeaderData::default();
-
-            let cmsgs: Vec<_> = match recvmsg::<()>(stream.as_raw_fd(), &mut header.vectors(), Some(&mut cmsg_buf), MsgFlags::empty()) {
-                Ok(res) => {
-                    if res.bytes == 0 {
-                        println!(">> done <<");
-                        break;
-                    }
-                    res.cmsgs()?.collect()
-                },
+            let frame = match frame::recv(stream.as_raw_fd()) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    report::event(self.format, "disconnected", ">> done <<", report::no_fields());
+                    break;
+                }
                 Err(Errno::EAGAIN) => continue,
-                Err(e) => bail!("recvmsg 1 failed: {e}"),
+                Err(e) => bail!("recvmsg failed: {e}"),
             };
 
-            stream.readable().await?;
-            let mut payload: PayloadData = header.into();
-            let sz = match  recvmsg::<()>(stream.as_raw_fd(), &mut payload.vectors(), None, MsgFlags::empty()) {
-                Ok(res) => res.bytes,
-                Err(e) => bail!("recvmsg 2 failed: {e}"),
-            };
+            // println!("payload 1: {}", frame.d1.len());
+            // println!("payload2- {}", String::from_utf8_lossy(frame.d2.as_slice()));
 
-            // println!("payload 1: {}", payload.d1.len());
-            // println!("payload2- {}", String::from_utf8_lossy(payload.d2.as_slice()));
-
-            match (sz, cmsgs) {
-                (0, _) => break,
-                (_, cmsgs) => match bincode::deserialize::<FileMetadata>(payload.d1.as_slice()) {
-                    Ok(metadata) => {
-                        i += 1;
-                        //println!("=========={i} From iov==========");
-
-                        let mut received_fd: Option<File> = None;
-                        for cmsg in cmsgs {
-                            match cmsg {
-                                ControlMessageOwned::ScmRights(fds) => {
-                                    if !fds.is_empty() {
-                                        // take ownership of the fd
-                                        received_fd = Some(unsafe { File::from_raw_fd(fds[0]) });
-                                        println!("\tfd: {}", fds[0]);
-                                    }
-                                }
-                                other => {
-                                    println!("\tother ctrl-msg: {other:?}");
-                                }
-                            }
-                        }
-                        if let Some(file) = received_fd {
-                            self.consumer.send(Msg {
-                                id: i,
-                                metadata,
-                                file,
-                            }).await?;
-
-                            // let mut file = fs::File::try_from(file)?;
-                            // let mut contents = String::new();
-                            // match file.read_to_string(&mut contents) {
-                            //     Ok(bytes_read) => {
-                            //         println!("<receiver id={i} size={bytes_read}>");
-                            //         // keep a running count of total bytes received
-                            //         self.total_received.fetch_add(bytes_read, Ordering::Relaxed);
-                            //     }
-                            //     Err(e) => {
-                            //         println!("error: could not read from file descriptor: {}", e);
-                            //     }
-                            // }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("failed to deserialize metadata: {e}");
+            match bincode::deserialize::<FileMetadata>(frame.d1.as_slice()) {
+                Ok(metadata) => {
+                    i += 1;
+                    //println!("=========={i} From iov==========");
+
+                    if let Some(fd) = frame.fds.into_iter().next() {
+                        let fields = json!({"id": i, "fd": fd.as_raw_fd()});
+                        report::event(self.format, "received", format!("\tfd: {}", fd.as_raw_fd()), fields);
+                        self.consumer.send(Msg::new(i, metadata, File::from(fd))).await?;
+
+                        // let mut file = fs::File::try_from(file)?;
+                        // let mut contents = String::new();
+                        // match file.read_to_string(&mut contents) {
+                        //     Ok(bytes_read) => {
+                        //         println!("<receiver id={i} size={bytes_read}>");
+                        //         // keep a running count of total bytes received
+                        //         self.total_received.fetch_add(bytes_read, Ordering::Relaxed);
+                        //     }
+                        //     Err(e) => {
+                        //         println!("error: could not read from file descriptor: {}", e);
+                        //     }
+                        // }
+                    } else {
+                        // eg a fifo sent without --opath, there is nothing to hand the chain
+                        let text = format!("\tno fd for {}, metadata only", metadata.path);
+                        report::event(self.format, "metadata_only", text, json!({"id": i, "metadata": metadata}));
                     }
                 }
+                Err(e) => {
+                    let text = format!("failed to deserialize metadata: {e}");
+                    report::error(self.format, "metadata_error", text, json!({"error": e.to_string()}));
+                }
             }
         }
 
@@ -175,54 +275,8 @@ impl SocketRx {
 impl Drop for SocketRx {
     fn drop(&mut self) {
         if fs::remove_file(&self.socket_path).is_err() {
-            println!("rx: error rm socket file {}", self.socket_path);
-        }
-    }
-}
-
-#[derive(Default)]
-struct HeaderData {
-    t: [u8; 2],
-    s1: [u8; 2],
-    s2: [u8; 2],
-}
-impl HeaderData {
-    fn t(&self) -> u16 {
-        u16::from_ne_bytes(self.t)
-    }
-    fn s1(&self) -> u16 {
-        u16::from_ne_bytes(self.s1)
-    }
-    fn s2(&self) -> u16 {
-        u16::from_ne_bytes(self.s2)
-    }
-    fn vectors(&mut self) -> Vec<IoSliceMut<'_>> {
-        vec![IoSliceMut::new(&mut self.t), IoSliceMut::new(&mut self.s1), IoSliceMut::new(&mut self.s2)]
-    }
-}
-
-struct PayloadData {
-    s1: u16,
-    s2: u16,
-    d1: Vec<u8>,
-    d2: Vec<u8>,
-}
-
-impl PayloadData {
-    fn new(s1: u16, s2: u16) -> Self {
-        PayloadData {
-            s1, s2,
-            d1: vec![0; s1 as usize],
-            d2: vec![0; s2 as usize],
+            let text = format!("rx: error rm socket file {}", self.socket_path);
+            report::error(self.format, "cleanup_error", text, json!({"socket": self.socket_path}));
         }
     }
-    fn vectors(&mut self) -> Vec<IoSliceMut<'_>> {
-        vec![IoSliceMut::new(&mut self.d1), IoSliceMut::new(&mut self.d2)]
-    }
-}
-
-impl From<HeaderData> for PayloadData {
-    fn from(hd: HeaderData) -> Self {
-        PayloadData::new(hd.s1(), hd.s2())
-    }
 }
@@ -0,0 +1,146 @@
+// what rx has to say, as text for people or json lines for scripts
+//
+// consumers don't print, they add to the Report of the file they handle and
+// it is emitted in one piece once the whole chain is done with the file
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+// set once something else writes its output to stdout
+static STDOUT_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    /// one json object per line
+    Json,
+}
+
+/// everything said about one file, shared so it outlives a chain that drops the Msg
+#[derive(Debug, Clone, Default)]
+pub struct Report(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    lines: Vec<String>,
+    fields: Map<String, Value>,
+    errors: Vec<String>,
+}
+
+impl Report {
+    fn inner(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// a line of the text output
+    pub fn line(&self, line: impl Into<String>) {
+        self.inner().lines.push(line.into());
+    }
+
+    /// a field of the json output
+    pub fn set(&self, key: &str, value: impl Serialize) {
+        let value = serde_json::to_value(value).unwrap_or_else(|e| Value::String(e.to_string()));
+        self.inner().fields.insert(key.to_string(), value);
+    }
+
+    pub fn error(&self, error: impl Display) {
+        let mut inner = self.inner();
+        inner.lines.push(format!("\t{error}"));
+        inner.errors.push(error.to_string());
+    }
+
+    /// the whole report, newline terminated
+    pub fn render(&self, id: usize, format: Format) -> String {
+        let inner = self.inner();
+        match format {
+            Format::Text => {
+                let mut out = format!("<consumer id={id}>\n");
+                for line in &inner.lines {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out.push_str("</consumer>\n");
+                out
+            }
+            Format::Json => {
+                let mut object = Map::new();
+                object.insert("type".into(), "file".into());
+                object.insert("id".into(), id.into());
+                object.extend(inner.fields.clone());
+                object.insert("errors".into(), inner.errors.clone().into());
+                format!("{}\n", Value::Object(object))
+            }
+        }
+    }
+}
+
+/// send reports and events to stderr from now on, stdout belongs to eg an archive
+pub fn claim_stdout() {
+    STDOUT_CLAIMED.store(true, Ordering::Relaxed);
+}
+
+pub fn stdout_claimed() -> bool {
+    STDOUT_CLAIMED.load(Ordering::Relaxed)
+}
+
+/// a rendered report, or anything else that belongs with them, as is
+pub fn emit(text: impl Display) {
+    if stdout_claimed() {
+        eprint!("{text}");
+    } else {
+        print!("{text}");
+    }
+}
+
+/// something that happened outside of any one file, like a connection
+/// fields should be a json object
+pub fn event(format: Format, kind: &str, text: impl Display, fields: Value) {
+    match format {
+        Format::Text => emit(format_args!("{text}\n")),
+        Format::Json => emit(format_args!("{}\n", event_json(kind, fields))),
+    }
+}
+
+/// an event that is an error, text goes to stderr
+pub fn error(format: Format, kind: &str, text: impl Display, fields: Value) {
+    match format {
+        Format::Text => eprintln!("{text}"),
+        Format::Json => emit(format_args!("{}\n", event_json(kind, fields))),
+    }
+}
+
+fn event_json(kind: &str, fields: Value) -> Value {
+    let mut object = Map::new();
+    object.insert("type".into(), "event".into());
+    object.insert("event".into(), kind.into());
+    if let Value::Object(fields) = fields {
+        object.extend(fields);
+    }
+    Value::Object(object)
+}
+
+/// shorthand for events without fields
+pub fn no_fields() -> Value {
+    json!({})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_text_and_json() {
+        let report = Report::default();
+        report.line("\tPath: a");
+        report.set("path", "a");
+        report.error("failed to copy: nope");
+        assert_eq!(report.render(3, Format::Text), "<consumer id=3>\n\tPath: a\n\tfailed to copy: nope\n</consumer>\n");
+        let json: Value = serde_json::from_str(&report.render(3, Format::Json)).unwrap();
+        assert_eq!(json, json!({"type": "file", "id": 3, "path": "a", "errors": ["failed to copy: nope"]}));
+        assert_eq!(event_json("connected", json!({"pid": 1})), json!({"type": "event", "event": "connected", "pid": 1}));
+    }
+}
@@ -0,0 +1,212 @@
+// directory traversal for tx
+//
+// entries are read a whole directory at a time and sorted by name so the
+// order is deterministic, the root itself is never yielded unless it is a
+// symlink that isn't followed, then it is the only entry
+
+use crate::filter::{load_ignores, Filter, Verdict};
+use ignore::gitignore::Gitignore;
+use std::collections::{HashSet, VecDeque};
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Order {
+    /// every entry of a dir before any of its subdirs
+    #[default]
+    Breadth,
+    /// each subdir in full before its next sibling
+    Depth,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Follow {
+    /// symlinks are sent as symlinks, a symlinked source dir too
+    Never,
+    /// only a symlinked source dir is followed
+    #[default]
+    CommandLine,
+    /// every symlink is followed, loops are skipped
+    Always,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanOpts {
+    /// None walks the whole tree, Some(1) is just the source dir
+    pub max_depth: Option<usize>,
+    pub order: Order,
+    pub follow: Follow,
+    pub filter: Option<Filter>,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    pub depth: usize,
+}
+
+// ignore files in effect for an entry, outermost first
+type Ignores = Arc<Vec<Arc<Gitignore>>>;
+
+pub struct Walker {
+    opts: ScanOpts,
+    pending: VecDeque<(Entry, Ignores)>,
+    // (dev, inode) of every dir walked so far
+    visited: HashSet<(u64, u64)>,
+}
+
+impl Walker {
+    pub async fn new<P: AsRef<Path>>(root: P, opts: ScanOpts) -> anyhow::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let metadata = match opts.follow {
+            Follow::Never => tokio::fs::symlink_metadata(&root).await?,
+            Follow::CommandLine | Follow::Always => tokio::fs::metadata(&root).await?,
+        };
+        let mut walker = Self {
+            opts,
+            pending: VecDeque::new(),
+            visited: HashSet::new(),
+        };
+        if metadata.is_symlink() {
+            // not followed, so there is nothing beneath it to walk
+            walker.pending.push_back((Entry { path: root, metadata, depth: 0 }, Arc::new(vec![])));
+            return Ok(walker);
+        }
+        walker.visited.insert((metadata.dev(), metadata.ino()));
+        let ignores = walker.ignores_for(&root, &Arc::new(vec![]));
+        let children = walker.read_children(&root, 1).await?;
+        walker.pending.extend(children.into_iter().map(|c| (c, ignores.clone())));
+        Ok(walker)
+    }
+
+    pub async fn next(&mut self) -> anyhow::Result<Option<Entry>> {
+        loop {
+            let Some((entry, ignores)) = self.pending.pop_front() else {
+                return Ok(None);
+            };
+
+            let verdict = match &self.opts.filter {
+                Some(filter) => filter.check(&entry.path, &entry.metadata, &ignores),
+                None => Verdict::Select,
+            };
+            if verdict == Verdict::Prune {
+                continue;
+            }
+
+            let within_depth = self.opts.max_depth.is_none_or(|max| entry.depth < max);
+            if entry.metadata.is_dir() && within_depth {
+                if self.visited.insert((entry.metadata.dev(), entry.metadata.ino())) {
+                    self.expand(&entry, &ignores).await;
+                } else {
+                    println!("tx: skipping {}, already visited", entry.path.display());
+                }
+            }
+
+            if verdict == Verdict::Select {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    async fn expand(&mut self, dir: &Entry, ignores: &Ignores) {
+        let ignores = self.ignores_for(&dir.path, ignores);
+        match self.read_children(&dir.path, dir.depth + 1).await {
+            Ok(children) => match self.opts.order {
+                Order::Breadth => self.pending.extend(children.into_iter().map(|c| (c, ignores.clone()))),
+                Order::Depth => {
+                    for child in children.into_iter().rev() {
+                        self.pending.push_front((child, ignores.clone()));
+                    }
+                }
+            },
+            Err(e) => println!("tx: failed to read {}: {e}", dir.path.display()),
+        }
+    }
+
+    // the parent's ignores plus any ignore files in dir itself
+    fn ignores_for(&self, dir: &Path, parent: &Ignores) -> Ignores {
+        if !self.opts.filter.as_ref().is_some_and(Filter::gitignore) {
+            return parent.clone();
+        }
+        match load_ignores(dir) {
+            Some(gi) => {
+                let mut ignores = parent.as_ref().clone();
+                ignores.push(gi);
+                Arc::new(ignores)
+            }
+            None => parent.clone(),
+        }
+    }
+
+    async fn read_children(&self, dir: &Path, depth: usize) -> anyhow::Result<Vec<Entry>> {
+        let mut children = vec![];
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(e) = entries.next_entry().await? {
+            let path = e.path();
+            let mut metadata = tokio::fs::symlink_metadata(&path).await?;
+            if metadata.is_symlink() && self.opts.follow == Follow::Always {
+                // a dangling link stays a link
+                if let Ok(target) = tokio::fs::metadata(&path).await {
+                    metadata = target;
+                }
+            }
+            children.push(Entry { path, metadata, depth });
+        }
+        children.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+        Ok(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    async fn walk(root: &Path, opts: ScanOpts) -> anyhow::Result<Vec<String>> {
+        let mut walker = Walker::new(root, opts).await?;
+        let mut seen = vec![];
+        while let Some(e) = walker.next().await? {
+            seen.push(e.path.strip_prefix(root)?.to_string_lossy().to_string());
+        }
+        Ok(seen)
+    }
+
+    #[tokio::test]
+    async fn orders_depths_and_loops() -> anyhow::Result<()> {
+        let root = TestDir::new("scan");
+        fs::create_dir_all(root.join("a/aa"))?;
+        fs::create_dir_all(root.join("b"))?;
+        fs::write(root.join("a/aa/f"), "")?;
+        fs::write(root.join("b/f"), "")?;
+        fs::write(root.join("c"), "")?;
+        std::os::unix::fs::symlink(&*root, root.join("b/up"))?;
+
+        let bfs = walk(&root, ScanOpts::default()).await?;
+        assert_eq!(bfs, ["a", "b", "c", "a/aa", "b/f", "b/up", "a/aa/f"]);
+
+        let dfs = walk(&root, ScanOpts { order: Order::Depth, ..Default::default() }).await?;
+        assert_eq!(dfs, ["a", "a/aa", "a/aa/f", "b", "b/f", "b/up", "c"]);
+
+        let shallow = walk(&root, ScanOpts { max_depth: Some(1), ..Default::default() }).await?;
+        assert_eq!(shallow, ["a", "b", "c"]);
+
+        // b/up leads back to the root, it is yielded once but not walked again
+        let followed = walk(&root, ScanOpts { follow: Follow::Always, ..Default::default() }).await?;
+        assert_eq!(followed, bfs);
+
+        // a symlinked root is followed by default, with never it is the link itself
+        let link = root.join("link");
+        std::os::unix::fs::symlink(root.join("a"), &link)?;
+        let mut walker = Walker::new(&link, ScanOpts { follow: Follow::Never, ..Default::default() }).await?;
+        let entry = walker.next().await?.expect("the link");
+        assert_eq!((entry.path, entry.metadata.is_symlink()), (link.clone(), true));
+        assert!(walker.next().await?.is_none());
+        let followed = walk(&link, ScanOpts::default()).await?;
+        assert_eq!(followed, ["aa", "aa/f"]);
+        Ok(())
+    }
+}
@@ -1,50 +1,172 @@
 use anyhow::{bail, Context};
-use clap::Parser;
-use example_tokio_uds_fd::FileMetadata;
-use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags, UnixAddr};
+use clap::{Parser, Subcommand};
+use example_tokio_uds_fd::filter::{parse_size, Filter, FilterOpts};
+use example_tokio_uds_fd::hash::Algorithm;
+use example_tokio_uds_fd::mime::{Detectors, MimeTypes};
+use example_tokio_uds_fd::scan::{Entry, Follow, Order, ScanOpts, Walker};
+use example_tokio_uds_fd::serve::{serve, RootOpener};
+use example_tokio_uds_fd::{frame, memfd, reader, FileMetadata, FileType};
+use nix::fcntl::OFlag;
+use std::fs;
 use std::fs::File;
-use std::io::IoSlice;
+use std::io::{self, Read};
 use std::os::fd::IntoRawFd;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use tokio::net::UnixListener;
 use tokio::sync::mpsc;
 use tokio::task;
 
 #[derive(Debug, Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Opts {
     /// source dir of files to xfer, defaults to pwd
     #[clap(short = 'D', long, default_value = ".")]
     source_dir: PathBuf,
     /// existing socket to connect to (created by rx)
-    socket_path: PathBuf,
+    #[arg(required = true)]
+    socket_path: Option<PathBuf>,
+    /// walk subdirs of the source dir too
+    #[clap(short, long)]
+    recursive: bool,
+    /// how many levels below the source dir to walk, implies --recursive
+    #[clap(long)]
+    max_depth: Option<usize>,
+    /// order to walk subdirs in
+    #[clap(long, value_enum, default_value_t)]
+    order: Order,
+    /// which symlinks to follow
+    #[clap(long, value_enum, default_value_t)]
+    follow_symlinks: Follow,
+    #[command(flatten)]
+    filter: FilterOpts,
+    /// send O_PATH fds for devices, fifos and sockets too, symlinks always get one
+    #[clap(long)]
+    opath: bool,
+    /// include xattrs and acls in the metadata
+    #[clap(long)]
+    xattrs: bool,
+    /// hash file contents so the receiver can verify them
+    #[clap(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+    digest: Option<Algorithm>,
+    /// extra extension to mime type mappings, in the mime.types format
+    #[clap(long)]
+    mime_types: Option<PathBuf>,
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Mode {
+    /// listen for open requests and answer them with files from the source dir
+    Serve {
+        /// root dir that requested paths are relative to, defaults to pwd
+        #[clap(short = 'D', long, default_value = ".")]
+        source_dir: PathBuf,
+        /// extra extension to mime type mappings, in the mime.types format
+        #[clap(long)]
+        mime_types: Option<PathBuf>,
+        /// path to create socket at
+        socket_path: PathBuf,
+    },
+    /// send stdin, or generated data, as one sealed memfd instead of files from a dir
+    Memfd {
+        /// the path the receiver sees for it
+        #[clap(long, default_value = "stdin")]
+        name: String,
+        /// send this many bytes of generated data instead of stdin, eg 10k or 2G
+        #[clap(long, value_parser = parse_size)]
+        generate: Option<u64>,
+        /// hash the content so the receiver can verify it
+        #[clap(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "sha256")]
+        digest: Option<Algorithm>,
+        /// existing socket to connect to (created by rx)
+        socket_path: PathBuf,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opts = Opts::parse();
-    if opts.socket_path.is_dir() || opts.socket_path.is_file() {
-        bail!("{} is not a socket", opts.socket_path.display());
+    match opts.mode {
+        Some(Mode::Serve {
+            source_dir,
+            mime_types,
+            socket_path,
+        }) => {
+            let detectors = detectors(&source_dir, mime_types.as_deref())?;
+            serve_dir(source_dir, socket_path, detectors).await
+        }
+        Some(Mode::Memfd {
+            name,
+            generate,
+            digest,
+            socket_path,
+        }) => push_memfd(name, generate, digest, socket_path).await,
+        None => {
+            let detectors = detectors(&opts.source_dir, opts.mime_types.as_deref())?;
+            let scan = ScanOpts {
+                max_depth: opts.max_depth.or(if opts.recursive { None } else { Some(1) }),
+                order: opts.order,
+                follow: opts.follow_symlinks,
+                filter: Some(Filter::new(&opts.source_dir, opts.filter)?.with_detectors(detectors.clone())),
+            };
+            let send = SendOpts {
+                opath: opts.opath,
+                xattrs: opts.xattrs,
+                digest: opts.digest,
+                detectors,
+            };
+            push_dir(opts.source_dir, opts.socket_path.expect("socket path"), scan, send).await
+        }
     }
+}
 
-    for i in 0..=5 {
-        if !opts.socket_path.exists() && i == 5 {
-            bail!("{} is not a socket", opts.socket_path.display());
-        } else if opts.socket_path.exists() {
-            break
-        }
-        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+// the user's file and a .mime.types in the source dir beat the built in names, /etc/mime.types doesn't
+fn detectors(source_dir: &Path, mime_types: Option<&Path>) -> anyhow::Result<Detectors> {
+    let table = MimeTypes::discover(Some(source_dir), mime_types)?;
+    Ok(Detectors::default().with_system_types(MimeTypes::system()?).with_mime_types(table))
+}
+
+async fn serve_dir(source_dir: PathBuf, socket_path: PathBuf, detectors: Detectors) -> anyhow::Result<()> {
+    if socket_path.exists() {
+        fs::remove_file(&socket_path)?;
     }
 
-    let tx = SocketTx::new(&opts.socket_path);
+    println!("serving {} on socket: {}", source_dir.display(), socket_path.display());
+    let listener = UnixListener::bind(&socket_path)?;
+    fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o666))?;
+
+    ctrlc::set_handler({
+        let sock = socket_path.clone();
+        move || {
+            println!("\ndone...");
+            let _ = fs::remove_file(&sock);
+            exit(0);
+        }
+    })
+    .expect("ctrl+c");
+
+    println!("listening...");
+    serve(listener, Arc::new(RootOpener::new(source_dir).with_detectors(detectors))).await
+}
+
+async fn push_dir(source_dir: PathBuf, socket_path: PathBuf, scan: ScanOpts, send: SendOpts) -> anyhow::Result<()> {
+    wait_for_socket(&socket_path).await?;
+
+    let tx = SocketTx::new(&socket_path);
 
     println!(
         "tx metadata for all files in {}/* to {}",
-        opts.source_dir.display(),
-        opts.socket_path.display()
+        source_dir.display(),
+        socket_path.display()
     );
 
-    match tx.send_dir(opts.source_dir).await {
+    match tx.send_dir(source_dir, scan, send).await {
         Ok(()) => println!("done"),
         Err(e) => println!("error: {e}"),
     }
@@ -54,6 +176,73 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+// the data is in the memfd before anything connects, rx sees it all at once
+async fn push_memfd(name: String, generate: Option<u64>, digest: Option<Algorithm>, socket_path: PathBuf) -> anyhow::Result<()> {
+    wait_for_socket(&socket_path).await?;
+
+    let message = task::spawn_blocking(move || -> anyhow::Result<Msg> {
+        let file = match generate {
+            Some(len) => memfd::from_reader(&name, Generated { left: len, pos: 0 })?,
+            None => memfd::from_reader(&name, io::stdin().lock())?,
+        };
+        let mut meta = FileMetadata::detached(&name, &file)?;
+        meta.sniff_mime(&file, &Detectors::default());
+        if let Some(algorithm) = digest {
+            meta.compute_digest(&file, algorithm)?;
+        }
+        Ok(Msg { meta, file: Some(file) })
+    })
+    .await??;
+
+    println!("tx {} bytes as a sealed memfd to {}", message.meta.size, socket_path.display());
+    task::spawn_blocking(move || send_msg(&mut UnixStream::connect(socket_path)?, message)).await??;
+    println!("done");
+    Ok(())
+}
+
+// rx may still be starting up
+async fn wait_for_socket(socket_path: &Path) -> anyhow::Result<()> {
+    if socket_path.is_dir() || socket_path.is_file() {
+        bail!("{} is not a socket", socket_path.display());
+    }
+
+    for i in 0..=5 {
+        if !socket_path.exists() && i == 5 {
+            bail!("{} is not a socket", socket_path.display());
+        } else if socket_path.exists() {
+            break
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    }
+    Ok(())
+}
+
+/// a repeating byte pattern, cheap to make and easy to recognise in a preview
+struct Generated {
+    left: u64,
+    pos: u64,
+}
+
+impl Read for Generated {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        for b in &mut buf[..n] {
+            *b = (self.pos % 251) as u8;
+            self.pos += 1;
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SendOpts {
+    opath: bool,
+    xattrs: bool,
+    digest: Option<Algorithm>,
+    detectors: Detectors,
+}
+
 #[derive(Debug)]
 struct Msg {
     pub meta: FileMetadata,
@@ -71,7 +260,7 @@ impl SocketTx {
         }
     }
 
-    pub async fn send_dir<P: AsRef<Path>>(&self, src_dir: P) -> anyhow::Result<()> {
+    pub async fn send_dir<P: AsRef<Path>>(&self, src_dir: P, scan: ScanOpts, send: SendOpts) -> anyhow::Result<()> {
         let (tx, rx) = mpsc::channel::<Msg>(100);
 
         // spawn blocking
@@ -82,7 +271,7 @@ impl SocketTx {
 
         let scan = task::spawn({
             let src_dir = src_dir.as_ref().to_path_buf();
-            async move { scan_dir(src_dir, tx).await }
+            async move { scan_dir(src_dir, scan, send, tx).await }
         });
 
         let (scan_res, send_res) = tokio::try_join!(scan, syscall)?;
@@ -106,34 +295,28 @@ fn worker(socket_path: PathBuf, mut rx: mpsc::Receiver<Msg>) -> anyhow::Result<(
 // send Msg to the socket, requires blocking context
 // only split out to make error handling more concise
 fn send_msg(stream: &mut UnixStream, mut message: Msg) -> anyhow::Result<()> {
-    let serialized = bincode::serialize(&message.meta)?;
+    let had_xattrs = !message.meta.xattrs.is_empty();
+    let serialized = match frame::metadata(&mut message.meta)? {
+        Some(serialized) => serialized,
+        None => {
+            // the fd is closed with message, the rest of the transfer goes on
+            println!("tx: metadata of {} is too large for a frame, skipped", message.meta.path);
+            return Ok(());
+        }
+    };
+    if had_xattrs && message.meta.xattrs.is_empty() {
+        println!("tx: xattrs of {} are too large for a frame, sent without them", message.meta.path);
+    }
     let second_payload = "!*-*-*-*-*-*-*-*-*-*-*!";
-    let t = 1u16.to_ne_bytes();
-    let size1  = (serialized.len() as u16).to_ne_bytes();
     println!("size1: {}", serialized.len());
-    let size2  = (second_payload.len() as u16).to_ne_bytes();
-    let io_slice1 = IoSlice::new(&t);
-    let io_slice2 = IoSlice::new(&size1);
-    let io_slice3 = IoSlice::new(&size2);
-    let io_slice4 = IoSlice::new(&serialized);
-    let io_slice5 = IoSlice::new(second_payload.as_bytes());
-
-    let fd_array;
-    let control_messages = if let Some(file) = message.file.take() {
+
+    let fds = match message.file.take() {
         // give up ownership of the fd
-        fd_array = [file.into_raw_fd()];
-        vec![ControlMessage::ScmRights(&fd_array)]
-    } else {
-        vec![]
+        Some(file) => vec![file.into_raw_fd()],
+        None => vec![],
     };
 
-    sendmsg(
-        stream.as_raw_fd(),
-        &[io_slice1, io_slice2, io_slice3, io_slice4, io_slice5],
-        &control_messages,
-        MsgFlags::empty(),
-        None::<&UnixAddr>,
-    )
+    frame::send(stream.as_raw_fd(), frame::FILE, &serialized, second_payload.as_bytes(), &fds)
         .context("tx: failed to send message")?;
 
     println!("tx: sent {}", message.meta.path);
@@ -141,28 +324,67 @@ fn send_msg(stream: &mut UnixStream, mut message: Msg) -> anyhow::Result<()> {
     Ok(())
 }
 
-// read all files from src and send on tx
-async fn scan_dir<P: AsRef<Path>>(src: P, tx: mpsc::Sender<Msg>) -> anyhow::Result<()> {
-    let mut entries = tokio::fs::read_dir(src).await?;
-    while let Some(e) = entries.next_entry().await? {
-        let path = e.path();
-        let metadata = e.metadata().await?;
-
-        let meta = FileMetadata::new(&path, &metadata)?;
-        let file = if metadata.is_file() {
-            match File::open(&path) {
-                Ok(file) => Some(file),
-                Err(_) => {
-                    println!("tx: failed to open {}", path.display());
-                    None
-                }
+// walk src and send every entry on tx
+async fn scan_dir<P: AsRef<Path>>(src: P, scan: ScanOpts, send: SendOpts, tx: mpsc::Sender<Msg>) -> anyhow::Result<()> {
+    // links the walk followed are opened the same way, any other link is sent as itself
+    let follow = scan.follow == Follow::Always;
+    let mut walker = Walker::new(src, scan).await?;
+    while let Some(Entry { path, metadata, .. }) = walker.next().await? {
+        let nofollow = !follow || metadata.is_symlink();
+        let send = send.clone();
+        // reading the head and hashing the whole file must not hold up the runtime
+        let described = task::spawn_blocking(move || {
+            let described = describe(&path, nofollow, &send);
+            (path, described)
+        })
+        .await?;
+        match described {
+            (_, Ok((meta, file))) => {
+                // the worker will take it from here
+                tx.send(Msg { meta, file }).await?;
             }
-        } else {
-            None
-        };
-
-        // the worker will take it from here
-        tx.send(Msg { meta, file }).await?;
+            (path, Err(e)) => println!("tx: failed to open {}: {e}", path.display()),
+        }
     }
     Ok(())
 }
+
+// the entry is opened once, as O_PATH so a fifo or device can't block, and described from that fd.
+// whatever was swapped in since the walk lstat'd it is what is described and sent, a link is never followed
+// syscalls must be made in blocking context
+fn describe(path: &Path, nofollow: bool, send: &SendOpts) -> anyhow::Result<(FileMetadata, Option<File>)> {
+    let flags = if nofollow { OFlag::O_PATH | OFlag::O_NOFOLLOW } else { OFlag::O_PATH };
+    let handle = File::options().read(true).custom_flags(flags.bits()).open(path)?;
+    let mut meta = FileMetadata::from_fd(path, &handle)?;
+    if send.xattrs
+        && let Err(e) = meta.collect_xattrs(path)
+    {
+        println!("tx: failed to read xattrs of {}: {e}", path.display());
+    }
+    match meta.file_type {
+        // receivers can read a file and openat relative to a dir fd
+        FileType::RegularFile | FileType::Directory => {}
+        // the link itself, so its target reaches the receiver, nothing is actually opened
+        FileType::SymbolicLink => {
+            meta.dangling = fs::metadata(path).is_err();
+            return Ok((meta, Some(handle)));
+        }
+        // a stable reference to the entry itself
+        _ if send.opath => return Ok((meta, Some(handle))),
+        _ => return Ok((meta, None)),
+    }
+    let file = match reader::reopen(&handle) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("tx: failed to open {}: {e}", path.display());
+            return Ok((meta, None));
+        }
+    };
+    meta.sniff_mime(&file, &send.detectors);
+    if let Some(algorithm) = send.digest
+        && let Err(e) = meta.compute_digest(&file, algorithm)
+    {
+        println!("tx: failed to hash {}: {e}", path.display());
+    }
+    Ok((meta, Some(file)))
+}
@@ -0,0 +1,155 @@
+// pull mode, answer open requests with metadata + fd
+
+use crate::frame::{self, OpenRequest};
+use crate::mime::Detectors;
+use crate::{policy, reader, FileMetadata, FileType};
+use anyhow::bail;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::UnixListener;
+use tokio::sync::Semaphore;
+use tokio::task;
+
+/// decides what a request may open
+pub trait Opener: Send + Sync {
+    fn open(&self, req: &OpenRequest) -> anyhow::Result<(FileMetadata, File)>;
+}
+
+/// opens anything beneath a root directory
+pub struct RootOpener {
+    root: PathBuf,
+    detectors: Detectors,
+}
+
+impl RootOpener {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            detectors: Detectors::default(),
+        }
+    }
+
+    pub fn with_detectors(mut self, detectors: Detectors) -> Self {
+        self.detectors = detectors;
+        self
+    }
+}
+
+impl Opener for RootOpener {
+    fn open(&self, req: &OpenRequest) -> anyhow::Result<(FileMetadata, File)> {
+        if req.write {
+            bail!("{} can only be served read-only", req.path);
+        }
+        let rel = Path::new(&req.path);
+        if rel.components().any(|c| !matches!(c, Component::Normal(_) | Component::CurDir)) {
+            bail!("{} is not beneath the served root", req.path);
+        }
+        // the entry itself is opened first and described from the fd, so what is sent is what was checked.
+        // a symlinked dir on the way would lead out of the root, so none are followed
+        let (root, handle) = policy::open_beneath(&self.root, rel)?;
+        let mut metadata = FileMetadata::from_fd(rel, &handle)?;
+        match metadata.file_type {
+            FileType::SymbolicLink => {
+                // only whether the target exists within the root, anything else would tell what is outside it
+                let target = metadata.symlink_target.clone().unwrap_or_default();
+                let prefix = self.root.canonicalize()?;
+                metadata.dangling = !policy::reachable(&root, rel, Path::new(&target), &prefix);
+                // hand over the link itself, like tx --opath does
+                return Ok((metadata, handle));
+            }
+            // fifos and devices could block or have side effects when opened
+            FileType::RegularFile | FileType::Directory => {}
+            _ => return Ok((metadata, handle)),
+        }
+        let file = reader::reopen(&handle)?;
+        metadata.sniff_mime(&file, &self.detectors);
+        Ok((metadata, file))
+    }
+}
+
+/// connections served at once, any more wait to be accepted
+pub const MAX_CONNECTIONS: usize = 64;
+
+// accept connections on the socket, each one is served from its own blocking task
+pub async fn serve(listener: UnixListener, opener: Arc<dyn Opener>) -> anyhow::Result<()> {
+    let slots = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        // a slot before accepting, so a flood of clients waits in the backlog instead of taking every blocking thread
+        let slot = slots.clone().acquire_owned().await?;
+        let Ok((stream, _)) = listener.accept().await else {
+            break;
+        };
+        match stream.peer_cred() {
+            Ok(cred) => println!("connected... pid={:?} uid={}", cred.pid(), cred.uid()),
+            Err(_) => println!("connected..."),
+        }
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        task::spawn_blocking({
+            let opener = opener.clone();
+            move || {
+                if let Err(e) = handle(stream, opener.as_ref()) {
+                    eprintln!("error handling connection: {e}");
+                }
+                drop(slot);
+            }
+        });
+    }
+    Ok(())
+}
+
+// syscalls must be made in blocking context
+fn handle(stream: UnixStream, opener: &dyn Opener) -> anyhow::Result<()> {
+    while let Some(frame) = frame::recv(stream.as_raw_fd())? {
+        if frame.kind != frame::OPEN {
+            bail!("unexpected frame kind {}", frame.kind);
+        }
+        let req: OpenRequest = bincode::deserialize(&frame.d1)?;
+        match opener.open(&req) {
+            Ok((metadata, file)) => {
+                let serialized = bincode::serialize(&metadata)?;
+                // the peer has its own copy of the fd once sent, ours closes on drop
+                frame::send(stream.as_raw_fd(), frame::FILE, &serialized, &[], &[file.as_raw_fd()])?;
+                println!("served {}", req.path);
+            }
+            Err(e) => {
+                println!("refused {}: {e}", req.path);
+                frame::send(stream.as_raw_fd(), frame::ERROR, e.to_string().as_bytes(), &[], &[])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn symlinked_dirs_lead_nowhere() -> anyhow::Result<()> {
+        let dir = TestDir::new("serve");
+        fs::create_dir_all(dir.join("root/sub"))?;
+        fs::write(dir.join("root/sub/f"), "ok")?;
+        fs::write(dir.join("secret"), "secret")?;
+        symlink("..", dir.join("root/up"))?;
+        symlink("../secret", dir.join("root/out"))?;
+        symlink("sub/f", dir.join("root/near"))?;
+        let opener = RootOpener::new(dir.join("root"));
+        let req = |path: &str| OpenRequest { path: path.into(), write: false };
+
+        let (_, file) = opener.open(&req("sub/f"))?;
+        assert_eq!(fs::read_to_string(format!("/proc/self/fd/{}", file.as_raw_fd()))?, "ok");
+        assert!(opener.open(&req("up/secret")).is_err());
+        assert!(opener.open(&req("up/root/sub/f")).is_err());
+        // links themselves are served, but only tell whether their target is within the root
+        assert!(opener.open(&req("out"))?.0.dangling);
+        assert!(!opener.open(&req("near"))?.0.dangling);
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+// extended stat fields via statx
+//
+// falls back to plain stat metadata when statx isn't available, which
+// loses the birth time and the attribute flags
+
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::Metadata;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nanos: u32,
+}
+
+/// STATX_ATTR flags, only meaningful when the filesystem reports them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attributes {
+    pub compressed: bool,
+    pub immutable: bool,
+    pub append_only: bool,
+    pub nodump: bool,
+    pub encrypted: bool,
+    pub verity: bool,
+    pub dax: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedStat {
+    pub uid: u32,
+    pub gid: u32,
+    pub inode: u64,
+    pub device: u64,
+    pub nlink: u64,
+    pub blocks: u64,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+    /// not every filesystem records a birth time
+    pub btime: Option<Timestamp>,
+    pub attributes: Attributes,
+}
+
+impl ExtendedStat {
+    pub fn new(path: &Path, follow: bool) -> io::Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        statx(libc::AT_FDCWD, &c_path, flags)
+    }
+
+    /// whatever fd refers to, an O_PATH fd of a symlink describes the link
+    pub fn from_fd(fd: impl AsFd) -> io::Result<Self> {
+        statx(fd.as_fd().as_raw_fd(), c"", libc::AT_EMPTY_PATH)
+    }
+}
+
+fn statx(dirfd: RawFd, path: &CStr, flags: libc::c_int) -> io::Result<ExtendedStat> {
+    let mut buf = MaybeUninit::<libc::statx>::zeroed();
+    let mask = libc::STATX_BASIC_STATS | libc::STATX_BTIME;
+    let res = unsafe { libc::statx(dirfd, path.as_ptr(), flags, mask, buf.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stx = unsafe { buf.assume_init() };
+    Ok(ExtendedStat::from(&stx))
+}
+
+impl From<&libc::statx> for ExtendedStat {
+    fn from(stx: &libc::statx) -> Self {
+        let ts = |t: libc::statx_timestamp| Timestamp {
+            secs: t.tv_sec,
+            nanos: t.tv_nsec,
+        };
+        // a flag is only set if the filesystem supports it at all
+        let attr = |flag: libc::c_int| stx.stx_attributes_mask & stx.stx_attributes & flag as u64 != 0;
+        Self {
+            uid: stx.stx_uid,
+            gid: stx.stx_gid,
+            inode: stx.stx_ino,
+            device: libc::makedev(stx.stx_dev_major, stx.stx_dev_minor),
+            nlink: stx.stx_nlink as u64,
+            blocks: stx.stx_blocks,
+            atime: ts(stx.stx_atime),
+            mtime: ts(stx.stx_mtime),
+            ctime: ts(stx.stx_ctime),
+            btime: (stx.stx_mask & libc::STATX_BTIME != 0).then(|| ts(stx.stx_btime)),
+            attributes: Attributes {
+                compressed: attr(libc::STATX_ATTR_COMPRESSED),
+                immutable: attr(libc::STATX_ATTR_IMMUTABLE),
+                append_only: attr(libc::STATX_ATTR_APPEND),
+                nodump: attr(libc::STATX_ATTR_NODUMP),
+                encrypted: attr(libc::STATX_ATTR_ENCRYPTED),
+                verity: attr(libc::STATX_ATTR_VERITY),
+                dax: attr(libc::STATX_ATTR_DAX),
+            },
+        }
+    }
+}
+
+impl From<&Metadata> for ExtendedStat {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            inode: metadata.ino(),
+            device: metadata.dev(),
+            nlink: metadata.nlink(),
+            blocks: metadata.blocks(),
+            atime: Timestamp {
+                secs: metadata.atime(),
+                nanos: metadata.atime_nsec() as u32,
+            },
+            mtime: Timestamp {
+                secs: metadata.mtime(),
+                nanos: metadata.mtime_nsec() as u32,
+            },
+            ctime: Timestamp {
+                secs: metadata.ctime(),
+                nanos: metadata.ctime_nsec() as u32,
+            },
+            btime: None,
+            attributes: Attributes::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs::{self, File};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fn same(stat: &ExtendedStat, metadata: &Metadata) {
+        assert_eq!((stat.inode, stat.device, stat.nlink), (metadata.ino(), metadata.dev(), metadata.nlink()));
+        assert_eq!((stat.uid, stat.gid, stat.blocks), (metadata.uid(), metadata.gid(), metadata.blocks()));
+        assert_eq!(stat.mtime, Timestamp { secs: metadata.mtime(), nanos: metadata.mtime_nsec() as u32 });
+        assert_eq!(stat.ctime, Timestamp { secs: metadata.ctime(), nanos: metadata.ctime_nsec() as u32 });
+        // std gets the birth time from statx too, where the filesystem has one
+        let created = metadata.created().ok().map(|t| t.duration_since(std::time::UNIX_EPOCH).unwrap_or_default());
+        assert_eq!(stat.btime.map(|t| (t.secs as u64, t.nanos)), created.map(|d| (d.as_secs(), d.subsec_nanos())));
+    }
+
+    #[test]
+    fn matches_std_metadata() -> anyhow::Result<()> {
+        let dir = TestDir::new("stat");
+        let path = dir.join("f");
+        fs::write(&path, "some bytes")?;
+        std::os::unix::fs::symlink("f", dir.join("link"))?;
+
+        same(&ExtendedStat::new(&path, true)?, &fs::metadata(&path)?);
+        same(&ExtendedStat::from_fd(File::open(&path)?)?, &fs::metadata(&path)?);
+        same(&ExtendedStat::new(&dir.join("link"), false)?, &fs::symlink_metadata(dir.join("link"))?);
+        // the link itself through an O_PATH fd, not its target
+        let flags = libc::O_PATH | libc::O_NOFOLLOW;
+        let link = File::options().read(true).custom_flags(flags).open(dir.join("link"))?;
+        same(&ExtendedStat::from_fd(&link)?, &fs::symlink_metadata(dir.join("link"))?);
+        // and what from_fd makes of it
+        let meta = crate::FileMetadata::from_fd(&path, &File::open(&path)?)?;
+        assert_eq!((meta.size, meta.stat.inode), (10, fs::metadata(&path)?.ino()));
+        Ok(())
+    }
+}
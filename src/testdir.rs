@@ -0,0 +1,30 @@
+// scratch dirs for tests, removed on drop so a failed assertion doesn't leave them behind
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// an empty dir for this test in this process, whatever an earlier run left
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{name}-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).expect("test dir");
+        Self(path)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,120 @@
+// extended attributes, including security.* labels and the posix acl
+// xattrs (system.posix_acl_access, system.posix_acl_default)
+
+use serde::{Deserialize, Serialize};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Xattr {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+// every xattr on path itself, a symlink is not followed
+// a filesystem without xattr support just has none
+pub fn list(path: &Path) -> io::Result<Vec<Xattr>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match read_sized(|buf, len| unsafe { libc::llistxattr(c_path.as_ptr(), buf.cast(), len) }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut xattrs = vec![];
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name)?;
+        match read_sized(|buf, len| unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf.cast(), len) }) {
+            Ok(value) => xattrs.push(Xattr {
+                name: String::from_utf8_lossy(name).to_string(),
+                value,
+            }),
+            // removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+// set xattrs on an open file, eg a copy made from a received fd
+// returns the names that could not be set, security.* usually needs privileges
+pub fn apply(file: &File, xattrs: &[Xattr]) -> Vec<(String, io::Error)> {
+    let mut failed = vec![];
+    for x in xattrs {
+        let res = CString::new(x.name.as_str()).map_err(io::Error::from).and_then(|name| set(file, &name, &x.value));
+        if let Err(e) = res {
+            failed.push((x.name.clone(), e));
+        }
+    }
+    failed
+}
+
+fn set(file: &File, name: &CStr, value: &[u8]) -> io::Result<()> {
+    let res = unsafe { libc::fsetxattr(file.as_raw_fd(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// call f with a null buffer for the size, then for real, retrying if it grew in between
+fn read_sized<F: Fn(*mut u8, usize) -> isize>(f: F) -> io::Result<Vec<u8>> {
+    loop {
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = f(buf.as_mut_ptr(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::fs;
+
+    #[test]
+    fn user_xattrs_round_trip() -> anyhow::Result<()> {
+        let dir = TestDir::new("xattr");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::write(&a, "")?;
+        fs::write(&b, "")?;
+        let xattrs = vec![
+            Xattr { name: "user.one".into(), value: b"1".to_vec() },
+            Xattr { name: "user.empty".into(), value: vec![] },
+        ];
+        let failed = apply(&File::open(&a)?, &xattrs);
+        if failed.iter().any(|(_, e)| e.raw_os_error() == Some(libc::EOPNOTSUPP)) {
+            eprintln!("skipped, no user xattrs on {}", dir.display());
+            return Ok(());
+        }
+        assert!(failed.is_empty(), "{failed:?}");
+
+        // listed in no particular order, next to anything else there, eg a security label
+        let mut listed = list(&a)?;
+        listed.retain(|x| x.name.starts_with("user."));
+        listed.sort_by(|x, y| y.name.cmp(&x.name));
+        assert_eq!(listed, xattrs);
+        assert!(apply(&File::open(&b)?, &listed).is_empty());
+        let mut copied = list(&b)?;
+        copied.retain(|x| x.name.starts_with("user."));
+        copied.sort_by(|x, y| y.name.cmp(&x.name));
+        assert_eq!(copied, xattrs);
+        Ok(())
+    }
+}